@@ -9,15 +9,42 @@ use clap::ValueHint;
     long_about = "A non-modal plaintext editor with saving and loading functionality."
 )]
 pub struct Args {
-    /// Path to file
+    /// Paths to files
     #[clap(value_name = "FILE", value_hint = ValueHint::FilePath)]
-    pub file: Option<String>,
+    pub files: Vec<String>,
 
     /// Enable syntax highlighting
     #[clap(short, long)]
     pub syntax: bool,
 
-    /// Highlighting theme
-    #[clap(short, long, default_value = "base16-eighties.dark")]
-    pub theme: String,
+    /// Highlighting theme [default: base16-eighties.dark]
+    #[clap(short, long)]
+    pub theme: Option<String>,
+
+    /// Highlight as this language instead of detecting it (name or extension)
+    #[clap(short, long)]
+    pub lang: Option<String>,
+
+    /// Show line numbers
+    #[clap(short = 'n', long)]
+    pub line_numbers: bool,
+
+    /// Show line numbers relative to the cursor
+    #[clap(short, long)]
+    pub relative_numbers: bool,
+
+    /// Send copied text to the terminal clipboard (OSC 52)
+    #[clap(long)]
+    pub osc52: bool,
+
+    /// Keep a file as it was opened as FILE~ when first saving it
+    #[clap(short, long)]
+    pub backup: bool,
+
+    /// Open files read-only through a memory map, for files too large to load
+    ///
+    /// The files must not be truncated by another program while they are
+    /// open, or the editor is killed (SIGBUS) the next time it reads them.
+    #[clap(long)]
+    pub mmap: bool,
 }
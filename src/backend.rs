@@ -0,0 +1,210 @@
+use std::io::{Stdout, Write};
+
+use crossterm::{
+    cursor, queue,
+    style::{Attribute, Print, SetAttribute, SetStyle},
+    terminal::{self, Clear, ClearType},
+};
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::{clipboard::base64, screen::Cell};
+
+/// Where `Screen` sends its frames
+pub trait Backend {
+    fn size(&self) -> (u16, u16);
+
+    /// Blank the whole screen
+    fn clear(&mut self);
+
+    /// Draw cells at (x, y), given in row order
+    fn draw(&mut self, cells: &[(u16, u16, Cell)]);
+
+    /// Show the cursor at a position, or hide it
+    fn set_cursor(&mut self, cursor: Option<(u16, u16)>);
+
+    /// Put `text` on the terminal's clipboard, along with the next flush
+    fn set_clipboard(&mut self, text: &str);
+
+    /// Send everything drawn since the last flush
+    fn flush(&mut self);
+}
+
+/// The terminal on stdout. Commands are collected and written in one go on
+/// `flush`.
+pub struct CrosstermBackend {
+    out: Stdout,
+    commands: Vec<u8>,
+}
+
+impl CrosstermBackend {
+    pub fn new() -> Self {
+        CrosstermBackend {
+            out: std::io::stdout(),
+            commands: Vec::new(),
+        }
+    }
+}
+
+impl Backend for CrosstermBackend {
+    fn size(&self) -> (u16, u16) {
+        terminal::size().unwrap()
+    }
+
+    fn clear(&mut self) {
+        queue!(
+            self.commands,
+            SetAttribute(Attribute::Reset),
+            Clear(ClearType::All)
+        )
+        .unwrap();
+    }
+
+    fn draw(&mut self, cells: &[(u16, u16, Cell)]) {
+        if cells.is_empty() {
+            return;
+        }
+        queue!(self.commands, cursor::Hide).unwrap();
+
+        // where the terminal's cursor ends up after the last cell printed,
+        // and the style it prints with, so runs of cells need neither again
+        let mut position = None;
+        let mut style = None;
+        for (x, y, cell) in cells {
+            // the rest of a wide character drawn before it
+            if cell.symbol.is_empty() {
+                continue;
+            }
+
+            if position != Some((*x, *y)) {
+                queue!(self.commands, cursor::MoveTo(*x, *y)).unwrap();
+            }
+            if style != Some(cell.style) {
+                queue!(
+                    self.commands,
+                    SetAttribute(Attribute::Reset),
+                    SetStyle(cell.style)
+                )
+                .unwrap();
+                style = Some(cell.style);
+            }
+            queue!(self.commands, Print(&cell.symbol)).unwrap();
+            position = Some((x + cell.symbol.width().max(1) as u16, *y));
+        }
+        queue!(self.commands, SetAttribute(Attribute::Reset)).unwrap();
+    }
+
+    fn set_cursor(&mut self, cursor: Option<(u16, u16)>) {
+        match cursor {
+            Some((x, y)) => queue!(self.commands, cursor::MoveTo(x, y), cursor::Show).unwrap(),
+            None => queue!(self.commands, cursor::Hide).unwrap(),
+        }
+    }
+
+    fn set_clipboard(&mut self, text: &str) {
+        // ESC ] 52 ; c ; <base64> BEL
+        write!(self.commands, "\x1b]52;c;{}\x07", base64(text.as_bytes())).unwrap();
+    }
+
+    fn flush(&mut self) {
+        self.out.write_all(&self.commands).unwrap();
+        self.out.flush().unwrap();
+        self.commands.clear();
+    }
+}
+
+/// Keeps the screen in memory, for tests. Clones share the same screen, so a
+/// test can keep one to look at after handing the other to the editor.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct TestBackend {
+    screen: std::rc::Rc<std::cell::RefCell<TestScreen>>,
+}
+
+#[cfg(test)]
+struct TestScreen {
+    grid: crate::screen::Grid,
+    cursor: Option<(u16, u16)>,
+    drawn: usize,              // cells drawn since the last `take_drawn`
+    clipboard: Option<String>, // what was last put on the clipboard
+}
+
+#[cfg(test)]
+impl TestBackend {
+    pub fn new(width: u16, height: u16) -> Self {
+        TestBackend {
+            screen: std::rc::Rc::new(std::cell::RefCell::new(TestScreen {
+                grid: crate::screen::Grid::new(width, height),
+                cursor: None,
+                drawn: 0,
+                clipboard: None,
+            })),
+        }
+    }
+
+    /// Change the size, as a terminal would before sending a resize event
+    pub fn resize(&self, width: u16, height: u16) {
+        self.screen.borrow_mut().grid = crate::screen::Grid::new(width, height);
+    }
+
+    /// Text of row `y`, without trailing blanks
+    pub fn row(&self, y: u16) -> String {
+        let screen = self.screen.borrow();
+        let row: String = (0..screen.grid.width)
+            .map(|x| screen.grid.cell(x, y).symbol.as_str())
+            .collect();
+        row.trim_end().to_string()
+    }
+
+    pub fn cell(&self, x: u16, y: u16) -> Cell {
+        self.screen.borrow().grid.cell(x, y).clone()
+    }
+
+    pub fn cursor(&self) -> Option<(u16, u16)> {
+        self.screen.borrow().cursor
+    }
+
+    /// Number of cells drawn since the last call
+    pub fn take_drawn(&self) -> usize {
+        std::mem::take(&mut self.screen.borrow_mut().drawn)
+    }
+
+    pub fn clipboard(&self) -> Option<String> {
+        self.screen.borrow().clipboard.clone()
+    }
+}
+
+#[cfg(test)]
+impl Backend for TestBackend {
+    fn size(&self) -> (u16, u16) {
+        let screen = self.screen.borrow();
+        (screen.grid.width, screen.grid.height)
+    }
+
+    fn clear(&mut self) {
+        let (width, height) = self.size();
+        self.screen.borrow_mut().grid = crate::screen::Grid::new(width, height);
+    }
+
+    fn draw(&mut self, cells: &[(u16, u16, Cell)]) {
+        let mut screen = self.screen.borrow_mut();
+        for (x, y, cell) in cells {
+            // the rest of a wide character is emptied along with it
+            if !cell.symbol.is_empty() {
+                let width = cell.symbol.width().max(1) as u16;
+                screen.grid.set(*x, *y, &cell.symbol, width, cell.style);
+            }
+        }
+        screen.drawn += cells.len();
+    }
+
+    fn set_cursor(&mut self, cursor: Option<(u16, u16)>) {
+        self.screen.borrow_mut().cursor = cursor;
+    }
+
+    fn set_clipboard(&mut self, text: &str) {
+        self.screen.borrow_mut().clipboard = Some(text.to_string());
+    }
+
+    fn flush(&mut self) {}
+}
@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+
+use crate::screen::Screen;
+
+const KILL_RING_SIZE: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct Clipboard {
+    kill_ring: VecDeque<String>,
+    osc52: bool,
+    pub last_yank: Option<((usize, usize), (usize, usize))>, // span of the last paste, for yank-pop
+}
+
+impl Clipboard {
+    pub fn new(osc52: bool) -> Self {
+        Clipboard {
+            osc52,
+            ..Default::default()
+        }
+    }
+
+    /// Put `text` on top of the kill ring (and the terminal clipboard if enabled)
+    pub fn copy(&mut self, text: String, screen: &mut Screen) {
+        if text.is_empty() {
+            return;
+        }
+
+        if self.osc52 {
+            screen.set_clipboard(&text);
+        }
+
+        self.kill_ring.push_front(text);
+        self.kill_ring.truncate(KILL_RING_SIZE);
+    }
+
+    pub fn paste(&self) -> Option<&str> {
+        self.kill_ring.front().map(String::as_str)
+    }
+
+    /// Move the newest entry to the back of the ring and return the next one
+    pub fn rotate(&mut self) -> Option<&str> {
+        self.kill_ring.rotate_left(1.min(self.kill_ring.len()));
+        self.paste()
+    }
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::TestBackend;
+
+    #[test]
+    fn base64_padding() {
+        let encoded: Vec<String> = ["", "f", "fo", "foo", "foob", "fooba", "foobar"]
+            .iter()
+            .map(|text| base64(text.as_bytes()))
+            .collect();
+        assert_eq!(
+            encoded,
+            ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"]
+        );
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn kill_ring() {
+        let backend = TestBackend::new(10, 2);
+        let mut screen = Screen::new(Box::new(backend.clone()));
+        let mut clipboard = Clipboard::new(true);
+        assert_eq!(clipboard.rotate(), None);
+
+        for text in ["one", "two", "", "three"] {
+            clipboard.copy(text.to_string(), &mut screen);
+        }
+        assert_eq!(backend.clipboard().as_deref(), Some("three"));
+        assert_eq!(clipboard.paste(), Some("three"));
+        assert_eq!(clipboard.rotate(), Some("two"));
+        assert_eq!(clipboard.rotate(), Some("one"));
+        assert_eq!(clipboard.rotate(), Some("three"));
+    }
+}
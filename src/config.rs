@@ -0,0 +1,188 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use serde::Deserialize;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+use crate::{
+    config_dir,
+    textbuf::{LineNumbers, TextBuf},
+    TABLENGTH,
+};
+
+/// Settings each buffer has its own copy of, which `[filetype.<name>]`
+/// sections can change for one language. Unset ones come from the layer
+/// below.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettings {
+    pub tab_width: Option<usize>,
+    pub insert_spaces: Option<bool>,
+    pub line_numbers: Option<LineNumbers>,
+    pub wrap: Option<bool>,
+}
+
+impl FileSettings {
+    /// Take the settings `other` sets
+    fn merge(&mut self, other: &FileSettings) {
+        self.tab_width = other.tab_width.or(self.tab_width);
+        self.insert_spaces = other.insert_spaces.or(self.insert_spaces);
+        self.line_numbers = other.line_numbers.or(self.line_numbers);
+        self.wrap = other.wrap.or(self.wrap);
+    }
+}
+
+/// Settings from `config.toml` in the config directory and `.edrs.toml` in
+/// the project, the project's taking precedence
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub theme: Option<String>,
+    pub syntax: Option<bool>,
+    pub autosave: Option<u64>, // seconds after the last change, 0 for never
+    pub tab_width: Option<usize>,
+    pub insert_spaces: Option<bool>,
+    pub line_numbers: Option<LineNumbers>,
+    pub wrap: Option<bool>,
+    pub filetype: BTreeMap<String, FileSettings>,
+    #[serde(skip)]
+    pub overrides: FileSettings, // from the command line, over every file type
+}
+
+impl Config {
+    /// Read the user's config file, then the project's from the current
+    /// directory or the nearest one above it that has one. Also returns what
+    /// was wrong with them, to report.
+    pub fn load() -> (Config, Vec<String>) {
+        let user = config_dir().map(|dir| dir.join("config.toml"));
+        let project = std::env::current_dir().ok().and_then(|dir| {
+            dir.ancestors()
+                .map(|dir| dir.join(".edrs.toml"))
+                .find(|path| path.is_file())
+        });
+
+        let mut config = Config::default();
+        let mut problems = Vec::new();
+        for path in user.iter().chain(project.iter()) {
+            match Config::read(path) {
+                Ok(Some(layer)) => config.merge(layer),
+                Ok(None) => {}
+                Err(e) => problems.push(format!(
+                    "{}: {e}",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                )),
+            }
+        }
+        (config, problems)
+    }
+
+    /// Parse the config file at `path`, if there is one
+    fn read(path: &Path) -> Result<Option<Config>, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        toml::from_str(&text).map(Some).map_err(|e| match e.span() {
+            Some(span) => format!(
+                "line {}: {}",
+                text[..span.start].lines().count().max(1),
+                e.message()
+            ),
+            None => e.message().to_string(),
+        })
+    }
+
+    /// Take the settings `other` sets
+    fn merge(&mut self, other: Config) {
+        self.theme = other.theme.or(self.theme.take());
+        self.syntax = other.syntax.or(self.syntax);
+        self.autosave = other.autosave.or(self.autosave);
+        self.tab_width = other.tab_width.or(self.tab_width);
+        self.insert_spaces = other.insert_spaces.or(self.insert_spaces);
+        self.line_numbers = other.line_numbers.or(self.line_numbers);
+        self.wrap = other.wrap.or(self.wrap);
+        for (name, settings) in other.filetype {
+            self.filetype.entry(name).or_default().merge(&settings);
+        }
+    }
+
+    pub fn autosave(&self) -> Option<Duration> {
+        self.autosave
+            .filter(|&seconds| seconds > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// `[filetype.<name>]` sections that name no known language
+    pub fn unknown_filetypes(&self, syntax_set: &SyntaxSet) -> Vec<String> {
+        self.filetype
+            .keys()
+            .filter(|name| syntax_set.find_syntax_by_token(name).is_none())
+            .map(|name| format!("unknown file type '{name}'"))
+            .collect()
+    }
+
+    /// Give a newly opened buffer the settings for its language. A section
+    /// may name the language or one of its file extensions.
+    pub fn apply(&self, textbuf: &mut TextBuf, syntax: &SyntaxReference, syntax_set: &SyntaxSet) {
+        let mut settings = FileSettings {
+            tab_width: self.tab_width,
+            insert_spaces: self.insert_spaces,
+            line_numbers: self.line_numbers,
+            wrap: self.wrap,
+        };
+        for (name, section) in &self.filetype {
+            if syntax_set
+                .find_syntax_by_token(name)
+                .is_some_and(|found| found.name == syntax.name)
+            {
+                settings.merge(section);
+            }
+        }
+        settings.merge(&self.overrides);
+
+        textbuf.tab_width = settings.tab_width.unwrap_or(TABLENGTH).max(1);
+        textbuf.indent_width = textbuf.tab_width;
+        textbuf.insert_spaces = settings.insert_spaces.unwrap_or(true);
+        textbuf.line_numbers = settings.line_numbers.unwrap_or_default();
+        textbuf.wrap = settings.wrap.unwrap_or(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filetype_sections_and_overrides() {
+        let mut config: Config = toml::from_str(
+            r#"
+            tab_width = 8
+            wrap = true
+
+            [filetype.rust]
+            tab_width = 4
+            line_numbers = "relative"
+            "#,
+        )
+        .unwrap();
+        config.merge(toml::from_str("[filetype.rs]\ninsert_spaces = false").unwrap());
+        config.overrides.line_numbers = Some(LineNumbers::Absolute);
+
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let rust = syntax_set.find_syntax_by_extension("rs").unwrap();
+        let mut textbuf = TextBuf::new();
+        config.apply(&mut textbuf, rust, &syntax_set);
+        assert_eq!(textbuf.tab_width, 4);
+        assert!(!textbuf.insert_spaces);
+        assert!(textbuf.wrap);
+        assert_eq!(textbuf.line_numbers, LineNumbers::Absolute);
+
+        let plain = syntax_set.find_syntax_plain_text();
+        config.apply(&mut textbuf, plain, &syntax_set);
+        assert_eq!(textbuf.tab_width, 8);
+        assert!(textbuf.insert_spaces);
+
+        assert!(toml::from_str::<Config>("tabwidth = 2").is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clipboard::Clipboard,
+    config::Config,
+    editorconfig,
+    events::{self, Event},
+    keymap::{Chord, Keymap},
+    screen::Screen,
+    textbuf::TextBuf,
+    window::{Direction, Layout, Pane, Rect, Split},
+    SynHighlighter,
+};
+
+/// The open buffers, the panes showing them, and state shared between them.
+///
+/// The focused pane's view lives in its `TextBuf` so key handling can work on
+/// the buffer alone; other panes keep theirs in `Pane::view`.
+pub struct Editor {
+    pub buffers: Vec<Arc<Mutex<TextBuf>>>,
+    pub layout: Layout,
+    pub focus: usize, // index of the focused pane
+    pub area: Rect,   // screen area for panes, everything above the message line
+    pub screen: Screen,
+    pub clipboard: Clipboard,
+    pub config: Config, // settings for newly opened buffers
+    pub backup: bool,
+    pub mmap: bool, // open files memory mapped
+    pub keymap: Keymap,
+    pub pending_keys: Vec<Chord>, // the start of a key sequence typed so far
+    pub quit: bool,               // leave the main loop
+    loaders: Vec<JoinHandle<()>>,
+    watched: Arc<Mutex<Vec<Weak<Mutex<TextBuf>>>>>, // buffers the file watcher checks
+}
+
+// how often the file watcher looks for files changed on disk
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Editor {
+    pub fn new(
+        screen: Screen,
+        clipboard: Clipboard,
+        config: Config,
+        backup: bool,
+        mmap: bool,
+    ) -> Self {
+        let (width, height) = screen.size();
+
+        Editor {
+            buffers: Vec::new(),
+            layout: Layout::Pane(Pane {
+                buffer: 0,
+                view: Default::default(),
+            }),
+            focus: 0,
+            area: Rect {
+                x: 0,
+                y: 0,
+                width,
+                height: height.saturating_sub(1),
+            },
+            screen,
+            clipboard,
+            config,
+            backup,
+            mmap,
+            keymap: Keymap::default(),
+            pending_keys: Vec::new(),
+            quit: false,
+            loaders: Vec::new(),
+            watched: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Start the thread that looks for open files changed on disk. It stops
+    /// once the editor is dropped.
+    pub fn spawn_file_watcher(&self) {
+        let watched = Arc::downgrade(&self.watched);
+        std::thread::spawn(move || watch_files(&watched));
+    }
+
+    /// Index of the buffer in the focused pane
+    pub fn current_index(&self) -> usize {
+        self.layout.pane(self.focus).buffer
+    }
+
+    pub fn current(&self) -> &Arc<Mutex<TextBuf>> {
+        &self.buffers[self.current_index()]
+    }
+
+    /// Open a new buffer, loading `filename` in the background, and make it
+    /// current. A file that is already open is switched to instead. The
+    /// buffer gets the settings for the language `sh` picks for the file.
+    pub fn open(&mut self, filename: Option<String>, sh: &SynHighlighter) {
+        if let Some(filename) = &filename {
+            if let Some(idx) = self
+                .buffers
+                .iter()
+                .position(|buffer| buffer.lock().unwrap().filename.as_ref() == Some(filename))
+            {
+                self.switch_to(idx);
+                return;
+            }
+        }
+
+        let mut textbuf = TextBuf::new();
+        let syntax = sh
+            .syntax_for_file(filename.as_deref())
+            .unwrap_or_else(|| sh.syntax_set.find_syntax_plain_text());
+        self.config.apply(&mut textbuf, syntax, &sh.syntax_set);
+        if let Some(filename) = &filename {
+            editorconfig::properties_for(Path::new(filename)).apply(&mut textbuf);
+        }
+        textbuf.backup = self.backup;
+        let textbuf = Arc::new(Mutex::new(textbuf));
+
+        if let Some(filename) = filename {
+            self.load(filename, &textbuf);
+        }
+        // new buffers are watched too, once they are saved somewhere
+        self.watched.lock().unwrap().push(Arc::downgrade(&textbuf));
+
+        self.buffers.push(textbuf);
+        self.switch_to(self.buffers.len() - 1);
+    }
+
+    fn load(&mut self, filename: String, textbuf: &Arc<Mutex<TextBuf>>) {
+        let textbuf = Arc::clone(textbuf);
+        let load = if self.mmap {
+            TextBuf::async_map
+        } else {
+            TextBuf::async_load
+        };
+        self.loaders.push(std::thread::spawn(move || {
+            if let Err(e) = load(&filename, &textbuf) {
+                let mut textbuf = textbuf.lock().unwrap();
+                textbuf.loading = None;
+                textbuf.set_message(format!("Error loading file: {}", e));
+                events::send(Event::Loaded);
+            }
+        }));
+    }
+
+    /// Reload buffers whose file the watcher saw change. Buffers with unsaved
+    /// changes are left alone with a warning.
+    pub fn reload_changed_files(&mut self) {
+        for buffer in self.buffers.clone() {
+            let mut textbuf = buffer.lock().unwrap();
+            if !std::mem::take(&mut textbuf.changed_on_disk) {
+                continue;
+            }
+
+            let Some(filename) = textbuf.filename.clone() else {
+                continue;
+            };
+            if textbuf.save_changed {
+                textbuf.set_message(format!(
+                    "{filename} changed on disk, saving will overwrite it"
+                ));
+            } else {
+                textbuf.reset();
+                textbuf.set_message(format!("Reloaded {filename}"));
+                drop(textbuf);
+                self.load(filename, &buffer);
+            }
+        }
+    }
+
+    /// Whether any pane needs to be drawn again
+    pub fn needs_redraw(&self) -> bool {
+        self.layout
+            .panes(self.area)
+            .iter()
+            .any(|(pane, _)| self.buffers[pane.buffer].lock().unwrap().dirty)
+    }
+
+    /// Mark every buffer as drawn
+    pub fn drawn(&mut self) {
+        for buffer in &self.buffers {
+            buffer.lock().unwrap().dirty = false;
+        }
+    }
+
+    /// When the main loop has to wake up next: to clear the message on the
+    /// message line once it times out, or to autosave a buffer
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let message = self.current().lock().unwrap().message_deadline();
+        let autosave = self
+            .buffers
+            .iter()
+            .filter_map(|buffer| self.autosave_deadline(&buffer.lock().unwrap()))
+            .min();
+        message.into_iter().chain(autosave).min()
+    }
+
+    pub fn expire_messages(&mut self) {
+        self.current().lock().unwrap().expire_message();
+    }
+
+    /// When `textbuf` is due to be autosaved, if it has changes to save
+    fn autosave_deadline(&self, textbuf: &TextBuf) -> Option<Instant> {
+        let interval = self.config.autosave()?;
+        if textbuf.filename.is_none() || textbuf.read_only_reason().is_some() {
+            return None;
+        }
+        textbuf.last_edit.map(|edited| edited + interval)
+    }
+
+    /// Save the buffers that have gone unchanged for the autosave interval
+    /// since they were last edited
+    pub fn autosave(&mut self) {
+        for buffer in &self.buffers {
+            let mut textbuf = buffer.lock().unwrap();
+            let due = self
+                .autosave_deadline(&textbuf)
+                .is_some_and(|deadline| deadline <= Instant::now());
+            if !due {
+                continue;
+            }
+
+            if let Err(e) = textbuf.save() {
+                // try again after the next change rather than right away
+                textbuf.last_edit = None;
+                textbuf.set_message(format!("Autosave failed: {e}"));
+            }
+            textbuf.dirty = true;
+        }
+    }
+
+    /// Show buffer `idx` in the focused pane
+    pub fn switch_to(&mut self, idx: usize) {
+        self.layout.pane_mut(self.focus).buffer = idx;
+        self.arrange();
+    }
+
+    pub fn next_buffer(&mut self) {
+        self.switch_to((self.current_index() + 1) % self.buffers.len());
+    }
+
+    pub fn previous_buffer(&mut self) {
+        self.switch_to((self.current_index() + self.buffers.len() - 1) % self.buffers.len());
+    }
+
+    /// Name of every buffer for display, with a marker on modified ones
+    pub fn buffer_names(&self) -> Vec<String> {
+        self.buffers
+            .iter()
+            .map(|buffer| {
+                let textbuf = buffer.lock().unwrap();
+                format!(
+                    "{}{}",
+                    textbuf.filename.as_deref().unwrap_or("[No Name]"),
+                    if textbuf.save_changed { " [+]" } else { "" }
+                )
+            })
+            .collect()
+    }
+
+    /// Move the focus to pane `idx`, handing the view over between buffers
+    pub fn focus_pane(&mut self, idx: usize) {
+        if idx == self.focus {
+            return;
+        }
+
+        let view = self.current().lock().unwrap().view();
+        self.layout.pane_mut(self.focus).view = view;
+
+        self.focus = idx;
+        let view = self.layout.pane(idx).view;
+        self.current().lock().unwrap().set_view(view);
+        self.arrange();
+    }
+
+    pub fn focus_direction(&mut self, direction: Direction) {
+        if let Some(idx) = self.layout.neighbour(self.focus, direction, self.area) {
+            self.focus_pane(idx);
+        }
+    }
+
+    /// Split the focused pane in two showing the same view. Returns false if
+    /// the pane is too small.
+    pub fn split(&mut self, split: Split) -> bool {
+        let rect = self.layout.panes(self.area)[self.focus].1;
+        let too_small = match split {
+            Split::Horizontal => rect.height < 4, // text and status line each
+            Split::Vertical => rect.width < 3,
+        };
+        if too_small {
+            return false;
+        }
+
+        let view = self.current().lock().unwrap().view();
+        self.layout.pane_mut(self.focus).view = view;
+        self.layout.split(self.focus, split);
+        self.arrange();
+        true
+    }
+
+    /// Close the focused pane. The last pane can't be closed.
+    pub fn close_pane(&mut self) -> bool {
+        let Some(idx) = self.layout.close(self.focus) else {
+            return false;
+        };
+
+        self.focus = idx;
+        let view = self.layout.pane(idx).view;
+        self.current().lock().unwrap().set_view(view);
+        self.arrange();
+        true
+    }
+
+    pub fn resize_pane(&mut self, direction: Direction) {
+        if self.layout.resize(self.focus, direction, self.area) {
+            self.arrange();
+        }
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.screen.resize(width, height);
+        self.area = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height: height.saturating_sub(1),
+        };
+        self.arrange();
+    }
+
+    /// Fit the focused buffer to its pane and redraw every pane
+    pub fn arrange(&mut self) {
+        for (idx, (pane, rect)) in self.layout.panes(self.area).into_iter().enumerate() {
+            let mut textbuf = self.buffers[pane.buffer].lock().unwrap();
+            if idx == self.focus {
+                textbuf.origin = (rect.x, rect.y);
+                textbuf.dimensions = (rect.width, rect.height);
+            }
+            textbuf.dirty = true;
+        }
+    }
+
+    /// Close every buffer and wait for the background loads, which stop once
+    /// their buffer is marked closed
+    pub fn join_loaders(&mut self) {
+        for buffer in self.buffers.drain(..) {
+            buffer.lock().unwrap().closed = true;
+        }
+        for loader in self.loaders.drain(..) {
+            loader.join().unwrap();
+        }
+    }
+}
+
+/// Poll the modification time of every watched file, flagging buffers whose
+/// file changed since it was loaded or saved
+fn watch_files(watched: &Weak<Mutex<Vec<Weak<Mutex<TextBuf>>>>>) {
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+
+        let Some(watched) = watched.upgrade() else {
+            return;
+        };
+        let buffers: Vec<_> = {
+            let mut watched = watched.lock().unwrap();
+            watched.retain(|buffer| buffer.strong_count() > 0);
+            watched.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        let mut changed = false;
+        for buffer in buffers {
+            let mut textbuf = buffer.lock().unwrap();
+            // memory mapped files only show what is on disk anyway
+            if textbuf.loading.is_some() || textbuf.mapped.is_some() {
+                continue;
+            }
+            let (Some(filename), Some(known)) = (&textbuf.filename, textbuf.disk_mtime) else {
+                continue;
+            };
+
+            let modified = std::fs::metadata(filename).and_then(|metadata| metadata.modified());
+            if let Some(modified) = modified.ok().filter(|&modified| modified != known) {
+                textbuf.disk_mtime = Some(modified);
+                textbuf.changed_on_disk = true;
+                changed = true;
+            }
+        }
+
+        if changed {
+            events::send(Event::FileChanged);
+        }
+    }
+}
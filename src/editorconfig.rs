@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+use crate::{
+    encoding::Encoding,
+    textbuf::{LineEnding, TextBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndentStyle {
+    Tab,
+    Space,
+}
+
+/// What `.editorconfig` files say about one file. Unset properties leave the
+/// editor's own settings alone.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Properties {
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub end_of_line: Option<LineEnding>,
+    pub charset: Option<Encoding>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+    pub max_line_length: Option<usize>,
+}
+
+// (section glob, properties in the section) of one `.editorconfig` file
+type Sections = Vec<(String, Vec<(String, String)>)>;
+
+/// Properties for the file at `path`, from the `.editorconfig` files in its
+/// directory and the ones above it up to one with `root = true`. Closer files
+/// and later sections take precedence.
+pub fn properties_for(path: &Path) -> Properties {
+    let path = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+
+    let mut files: Vec<(PathBuf, Sections)> = Vec::new();
+    for dir in path.ancestors().skip(1) {
+        let Ok(text) = std::fs::read_to_string(dir.join(".editorconfig")) else {
+            continue;
+        };
+        let (root, sections) = parse(&text);
+        files.push((dir.to_path_buf(), sections));
+        if root {
+            break;
+        }
+    }
+
+    let mut values = HashMap::new();
+    for (dir, sections) in files.iter().rev() {
+        let Ok(relative) = path.strip_prefix(dir) else {
+            continue;
+        };
+        let relative: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let relative = relative.join("/");
+
+        for (glob, properties) in sections {
+            if glob_matches(glob, &relative) {
+                values.extend(properties.iter().cloned());
+            }
+        }
+    }
+    Properties::from_values(&values)
+}
+
+/// Whether the file has `root = true` before its first section, and its
+/// sections. Keys and values are lowercased, as they are case insensitive.
+fn parse(text: &str) -> (bool, Sections) {
+    let mut root = false;
+    let mut sections: Sections = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+
+        if let Some(glob) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            sections.push((glob.to_string(), Vec::new()));
+        } else if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim().to_lowercase();
+            let value = value.trim().to_lowercase();
+            match sections.last_mut() {
+                Some((_, properties)) => properties.push((key, value)),
+                None if key == "root" => root = value == "true",
+                None => {}
+            }
+        }
+    }
+
+    (root, sections)
+}
+
+/// Whether a section's glob matches `path`, given relative to the directory
+/// of the `.editorconfig` file with `/` separators. Globs without a `/` match
+/// the file name in any directory below.
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let regex = match glob.strip_prefix('/') {
+        Some(glob) => format!("^{}$", glob_regex(glob)),
+        None if glob.contains('/') => format!("^{}$", glob_regex(glob)),
+        None => format!("^(?:.*/)?{}$", glob_regex(glob)),
+    };
+    Regex::new(&regex).is_ok_and(|regex| regex.is_match(path))
+}
+
+/// Translate an EditorConfig glob into a regex: `*`, `**`, `?`, `[chars]`,
+/// `[!chars]`, `{one,two}` and `{1..10}`
+fn glob_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let literal = |c: char| regex::escape(c.encode_utf8(&mut [0; 4]));
+
+    let mut regex = String::new();
+    let mut open_braces = 0;
+    let mut idx = 0;
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' if idx + 1 < chars.len() => {
+                idx += 1;
+                regex.push_str(&literal(chars[idx]));
+            }
+            '*' if chars.get(idx + 1) == Some(&'*') => {
+                idx += 1;
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match chars[idx..].iter().position(|&c| c == ']') {
+                Some(len) if len > 1 => {
+                    let class: String = chars[idx + 1..idx + len].iter().collect();
+                    let class = class.replace('\\', "\\\\").replace('[', "\\[");
+                    match class.strip_prefix('!') {
+                        Some(class) => regex.push_str(&format!("[^/{class}]")),
+                        None => regex.push_str(&format!("[{class}]")),
+                    }
+                    idx += len;
+                }
+                _ => regex.push_str("\\["),
+            },
+            '{' => {
+                let inner: String = chars[idx + 1..].iter().take_while(|&&c| c != '}').collect();
+                if let Some(range) = number_range(&inner) {
+                    regex.push_str(&range);
+                    idx += inner.chars().count() + 1;
+                } else if inner.contains(',') {
+                    regex.push_str("(?:");
+                    open_braces += 1;
+                } else {
+                    regex.push_str("\\{");
+                }
+            }
+            ',' if open_braces > 0 => regex.push('|'),
+            '}' if open_braces > 0 => {
+                regex.push(')');
+                open_braces -= 1;
+            }
+            c => regex.push_str(&literal(c)),
+        }
+        idx += 1;
+    }
+    regex
+}
+
+/// Regex for the integers from `n1` to `n2`, written `n1..n2`
+fn number_range(range: &str) -> Option<String> {
+    let (from, to) = range.split_once("..")?;
+    let (from, to): (i64, i64) = (from.parse().ok()?, to.parse().ok()?);
+    let (from, to) = (from.min(to), from.max(to));
+
+    // spell short ranges out, and accept any number for long ones
+    if to - from > 1000 {
+        return Some("-?[0-9]+".to_string());
+    }
+    let numbers: Vec<String> = (from..=to).map(|n| n.to_string()).collect();
+    Some(format!("(?:{})", numbers.join("|")))
+}
+
+impl Properties {
+    fn from_values(values: &HashMap<String, String>) -> Self {
+        // `unset` undoes a value from a file further up
+        let get = |key: &str| {
+            values
+                .get(key)
+                .map(String::as_str)
+                .filter(|&v| v != "unset")
+        };
+        let number = |key: &str| {
+            get(key)
+                .and_then(|value| value.parse::<usize>().ok())
+                .filter(|&n| n > 0)
+        };
+        let boolean = |key: &str| match get(key) {
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            _ => None,
+        };
+
+        let tab_width = number("tab_width").or(number("indent_size"));
+        let indent_size = match get("indent_size") {
+            Some("tab") => tab_width,
+            _ => number("indent_size"),
+        };
+
+        Properties {
+            indent_style: match get("indent_style") {
+                Some("tab") => Some(IndentStyle::Tab),
+                Some("space") => Some(IndentStyle::Space),
+                _ => None,
+            },
+            indent_size,
+            tab_width,
+            end_of_line: match get("end_of_line") {
+                Some("lf") => Some(LineEnding::Lf),
+                Some("crlf") => Some(LineEnding::CrLf),
+                Some("cr") => Some(LineEnding::Cr),
+                _ => None,
+            },
+            charset: get("charset").and_then(Encoding::from_name),
+            trim_trailing_whitespace: boolean("trim_trailing_whitespace"),
+            insert_final_newline: boolean("insert_final_newline"),
+            max_line_length: number("max_line_length"),
+        }
+    }
+
+    /// Give a newly opened buffer these settings
+    pub fn apply(&self, textbuf: &mut TextBuf) {
+        if let Some(style) = self.indent_style {
+            textbuf.insert_spaces = style == IndentStyle::Space;
+        }
+        if let Some(width) = self.tab_width {
+            textbuf.tab_width = width;
+        }
+        if let Some(size) = self.indent_size {
+            textbuf.indent_width = size;
+        }
+        textbuf.ruler = self.max_line_length;
+        textbuf.editorconfig = self.clone();
+        self.apply_format(textbuf);
+    }
+
+    /// Save the buffer in the format asked for, whatever the file was loaded
+    /// as. Binary files are left alone.
+    pub fn apply_format(&self, textbuf: &mut TextBuf) {
+        if textbuf.encoding == Encoding::Binary {
+            return;
+        }
+        if let Some(line_ending) = self.end_of_line {
+            textbuf.line_ending = line_ending;
+        }
+        if let Some(charset) = self.charset {
+            textbuf.encoding = charset;
+        }
+        if let Some(final_newline) = self.insert_final_newline {
+            textbuf.final_newline = final_newline;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*", "src/main.rs"));
+        assert!(glob_matches("*.rs", "src/main.rs"));
+        assert!(!glob_matches("/*.rs", "src/main.rs"));
+        assert!(glob_matches("src/*.rs", "src/main.rs"));
+        assert!(!glob_matches("src/*.rs", "src/bin/main.rs"));
+        assert!(glob_matches("src/**.rs", "src/bin/main.rs"));
+        assert!(glob_matches("*.{js,ts}", "web/app.ts"));
+        assert!(glob_matches("Makefile", "sub/Makefile"));
+        assert!(glob_matches("file[0-9].txt", "file7.txt"));
+        assert!(!glob_matches("file[!0-9].txt", "file7.txt"));
+        assert!(glob_matches("part{1..12}.md", "part10.md"));
+        assert!(!glob_matches("part{1..12}.md", "part13.md"));
+    }
+
+    #[test]
+    fn sections_and_values() {
+        let (root, sections) = parse(
+            "root = true\n\
+             \n\
+             [*]\n\
+             indent_style = space\n\
+             indent_size = 4\n\
+             end_of_line = LF\n\
+             \n\
+             # makefiles need tabs\n\
+             [Makefile]\n\
+             indent_style = tab\n\
+             indent_size = tab\n\
+             tab_width = 8\n\
+             max_line_length = unset\n",
+        );
+        assert!(root);
+
+        let properties = |path: &str| {
+            let mut values = HashMap::new();
+            for (glob, section) in &sections {
+                if glob_matches(glob, path) {
+                    values.extend(section.iter().cloned());
+                }
+            }
+            Properties::from_values(&values)
+        };
+
+        let rust = properties("src/main.rs");
+        assert_eq!(rust.indent_style, Some(IndentStyle::Space));
+        assert_eq!((rust.indent_size, rust.tab_width), (Some(4), Some(4)));
+        assert_eq!(rust.end_of_line, Some(LineEnding::Lf));
+
+        let makefile = properties("Makefile");
+        assert_eq!(makefile.indent_style, Some(IndentStyle::Tab));
+        assert_eq!(
+            (makefile.indent_size, makefile.tab_width),
+            (Some(8), Some(8))
+        );
+        assert_eq!(makefile.max_line_length, None);
+    }
+}
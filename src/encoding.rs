@@ -0,0 +1,295 @@
+use std::borrow::Cow;
+
+// Windows-1252 characters for bytes 0x80..=0x9F. The five bytes it leaves
+// undefined map to the matching C1 controls, as in Latin-1, so every byte
+// survives a round trip.
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+const HEX_BYTES_PER_LINE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Windows1252, // also covers Latin-1
+    Binary,      // shown as a read-only hex dump
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 BOM",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Windows1252 => "Windows-1252",
+            Encoding::Binary => "Binary",
+        }
+    }
+
+    /// Parse a name like `utf-16le` or `latin1`, ignoring case and separators
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+
+        match name.as_str() {
+            "utf8" => Some(Encoding::Utf8),
+            "utf8bom" => Some(Encoding::Utf8Bom),
+            "utf16le" => Some(Encoding::Utf16Le),
+            "utf16be" => Some(Encoding::Utf16Be),
+            "windows1252" | "cp1252" | "latin1" | "iso88591" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// What to decode a file as when it turns out not to be valid in this
+    /// encoding after all
+    pub fn fallback(&self) -> Encoding {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => Encoding::Windows1252,
+            _ => Encoding::Binary,
+        }
+    }
+
+    /// Byte order mark written at the start of the file
+    pub fn bom(&self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8Bom => b"\xEF\xBB\xBF",
+            Encoding::Utf16Le => b"\xFF\xFE",
+            Encoding::Utf16Be => b"\xFE\xFF",
+            _ => b"",
+        }
+    }
+
+    pub fn encode<'a>(&self, text: &'a str) -> Result<Cow<'a, [u8]>, std::io::Error> {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => Ok(Cow::Borrowed(text.as_bytes())),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+            Encoding::Windows1252 => text
+                .chars()
+                .map(|c| match c as u32 {
+                    0..=0x7F | 0xA0..=0xFF => Ok(c as u8),
+                    _ => match WINDOWS_1252.iter().position(|&other| other == c) {
+                        Some(idx) => Ok(0x80 + idx as u8),
+                        None => Err(std::io::Error::other(format!(
+                            "'{c}' can't be saved as {}",
+                            self.name()
+                        ))),
+                    },
+                })
+                .collect(),
+            Encoding::Binary => Err(std::io::Error::other("Binary files are read-only")),
+        }
+    }
+}
+
+/// Decodes a file read in chunks, carrying characters (and hex dump lines)
+/// split between chunks over to the next one
+#[derive(Debug)]
+pub struct Decoder {
+    pub encoding: Encoding,
+    pending: Vec<u8>,
+    offset: usize, // bytes decoded so far, for hex dump offsets
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Decoder {
+            encoding,
+            pending: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Pick the encoding from the start of a file: a byte order mark, then
+    /// UTF-8, falling back to Windows-1252. Files with NUL bytes (and no
+    /// UTF-16 BOM) are binary and decode to a hex dump. Returns the length of
+    /// the BOM to skip. `whole` says whether `start` is the entire file.
+    pub fn detect(start: &[u8], whole: bool) -> (Decoder, usize) {
+        let boms = [Encoding::Utf8Bom, Encoding::Utf16Le, Encoding::Utf16Be];
+        if let Some(encoding) = boms.into_iter().find(|e| start.starts_with(e.bom())) {
+            return (Decoder::new(encoding), encoding.bom().len());
+        }
+
+        let encoding = if start.contains(&0) {
+            Encoding::Binary
+        } else {
+            match std::str::from_utf8(start) {
+                Ok(_) => Encoding::Utf8,
+                // a character cut off by the end of the chunk
+                Err(e) if !whole && e.error_len().is_none() => Encoding::Utf8,
+                Err(_) => Encoding::Windows1252,
+            }
+        };
+        (Decoder::new(encoding), 0)
+    }
+
+    /// Decode the next chunk of the file. Returns `None` if it isn't valid in
+    /// the decoder's encoding.
+    pub fn decode(&mut self, chunk: &[u8], last: bool) -> Option<String> {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend_from_slice(chunk);
+
+        // hold back what can't be decoded until the next chunk arrives
+        let keep = match self.encoding {
+            _ if last => 0,
+            Encoding::Utf8 | Encoding::Utf8Bom => match std::str::from_utf8(&bytes) {
+                Err(e) if e.error_len().is_none() => bytes.len() - e.valid_up_to(),
+                _ => 0,
+            },
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let units = bytes.len() / 2;
+                let last_unit = units.checked_sub(1).map(|idx| self.utf16_unit(&bytes, idx));
+                // keep a high surrogate whose pair is in the next chunk
+                let split_pair = last_unit.is_some_and(|unit| (0xD800..0xDC00).contains(&unit));
+                bytes.len() % 2 + if split_pair { 2 } else { 0 }
+            }
+            Encoding::Binary => bytes.len() % HEX_BYTES_PER_LINE,
+            Encoding::Windows1252 => 0,
+        };
+        self.pending = bytes.split_off(bytes.len() - keep);
+        let offset = self.offset;
+        self.offset += bytes.len();
+
+        let text = match self.encoding {
+            Encoding::Utf8 | Encoding::Utf8Bom => String::from_utf8(bytes).ok()?,
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if !bytes.len().is_multiple_of(2) {
+                    return None;
+                }
+                char::decode_utf16((0..bytes.len() / 2).map(|idx| self.utf16_unit(&bytes, idx)))
+                    .collect::<Result<String, _>>()
+                    .ok()?
+            }
+            Encoding::Windows1252 => bytes
+                .iter()
+                .map(|&byte| match byte {
+                    0x80..=0x9F => WINDOWS_1252[byte as usize - 0x80],
+                    _ => byte as char,
+                })
+                .collect(),
+            Encoding::Binary => hex_dump(&bytes, offset),
+        };
+
+        Some(text)
+    }
+
+    fn utf16_unit(&self, bytes: &[u8], idx: usize) -> u16 {
+        let pair = [bytes[idx * 2], bytes[idx * 2 + 1]];
+        match self.encoding {
+            Encoding::Utf16Be => u16::from_be_bytes(pair),
+            _ => u16::from_le_bytes(pair),
+        }
+    }
+}
+
+/// Offset, hex bytes and printable ASCII, `HEX_BYTES_PER_LINE` bytes a line
+fn hex_dump(bytes: &[u8], offset: usize) -> String {
+    let mut dump = String::new();
+    for (idx, line) in bytes.chunks(HEX_BYTES_PER_LINE).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7E => byte as char,
+                _ => '.',
+            })
+            .collect();
+
+        dump.push_str(&format!(
+            "{:08x}  {:<width$}  |{ascii}|\n",
+            offset + idx * HEX_BYTES_PER_LINE,
+            hex.join(" "),
+            width = HEX_BYTES_PER_LINE * 3 - 1
+        ));
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // decode `bytes` as `encoding` split into chunks at `splits`
+    fn decode_split(encoding: Encoding, bytes: &[u8], splits: &[usize]) -> Option<String> {
+        let mut decoder = Decoder::new(encoding);
+        let mut text = String::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([&bytes.len()]) {
+            text.push_str(&decoder.decode(&bytes[start..end], end == bytes.len())?);
+            start = end;
+        }
+        Some(text)
+    }
+
+    #[test]
+    fn characters_split_between_chunks() {
+        // "a😀é": a surrogate pair, then a character split after its first byte
+        let utf16le: Vec<u8> = "a😀é".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        for split in 1..utf16le.len() {
+            assert_eq!(
+                decode_split(Encoding::Utf16Le, &utf16le, &[split]).as_deref(),
+                Some("a😀é")
+            );
+        }
+        let utf16be: Vec<u8> = "😀".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(
+            decode_split(Encoding::Utf16Be, &utf16be, &[1, 2, 3]).as_deref(),
+            Some("😀")
+        );
+        assert_eq!(decode_split(Encoding::Utf16Le, b"a\x00b", &[]), None);
+
+        let utf8 = "x€y".as_bytes();
+        for split in 1..utf8.len() {
+            assert_eq!(
+                decode_split(Encoding::Utf8, utf8, &[split]).as_deref(),
+                Some("x€y")
+            );
+        }
+        // cut off at the end of the file
+        assert_eq!(decode_split(Encoding::Utf8, &utf8[..2], &[]), None);
+    }
+
+    #[test]
+    fn detect_and_fall_back() {
+        let (decoder, bom) = Decoder::detect(b"\xFF\xFEa\x00", true);
+        assert_eq!((decoder.encoding, bom), (Encoding::Utf16Le, 2));
+        let (decoder, _) = Decoder::detect(b"abc\xE2\x82", false);
+        assert_eq!(decoder.encoding, Encoding::Utf8);
+        let (decoder, _) = Decoder::detect(b"abc\xE2\x82", true);
+        assert_eq!(decoder.encoding, Encoding::Windows1252);
+        let (decoder, _) = Decoder::detect(b"ab\x00c", true);
+        assert_eq!(decoder.encoding, Encoding::Binary);
+
+        // a later chunk that isn't UTF-8 after all
+        let mut decoder = Decoder::new(Encoding::Utf8);
+        assert_eq!(decoder.decode(b"plain ", false).as_deref(), Some("plain "));
+        assert_eq!(decoder.decode(b"caf\xE9", true), None);
+        assert_eq!(Encoding::Utf8.fallback(), Encoding::Windows1252);
+        assert_eq!(
+            decode_split(Encoding::Windows1252, b"plain caf\xE9", &[6]).as_deref(),
+            Some("plain café")
+        );
+    }
+
+    #[test]
+    fn windows_1252_round_trips_every_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let text = decode_split(Encoding::Windows1252, &bytes, &[]).unwrap();
+        assert_eq!(text.chars().count(), 256);
+        assert_eq!(Encoding::Windows1252.encode(&text).unwrap(), bytes);
+        assert!(Encoding::Windows1252.encode("😀").is_err());
+    }
+}
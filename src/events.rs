@@ -0,0 +1,76 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+use crate::io::{get_event, InputEvent};
+
+/// Everything the main loop waits for. Background threads keep their state in
+/// the buffers they work on; their events only wake the loop up to look at it.
+pub enum Event {
+    Input(InputEvent),
+    Loaded,      // a loader thread added text to a buffer
+    FileChanged, // an open file was changed on disk by another program
+    Timer,       // the deadline passed to `next` was reached
+}
+
+static CHANNEL: OnceLock<(Sender<Event>, Mutex<Receiver<Event>>)> = OnceLock::new();
+
+// events a prompt read while waiting for a key, for the main loop to handle
+// once the prompt is done
+static DEFERRED: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+fn channel() -> &'static (Sender<Event>, Mutex<Receiver<Event>>) {
+    CHANNEL.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        (sender, Mutex::new(receiver))
+    })
+}
+
+/// Queue an event for the main loop, from any thread
+pub fn send(event: Event) {
+    channel().0.send(event).unwrap();
+}
+
+/// Block until the next event, or until `deadline` if there is one
+pub fn next(deadline: Option<Instant>) -> Event {
+    let receiver = channel().1.lock().unwrap();
+    match deadline {
+        Some(deadline) => {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => Event::Timer,
+                Err(RecvTimeoutError::Disconnected) => unreachable!("the channel keeps a sender"),
+            }
+        }
+        None => receiver.recv().unwrap(),
+    }
+}
+
+/// The next event if one is already queued
+pub fn try_next() -> Option<Event> {
+    channel().1.lock().unwrap().try_recv().ok()
+}
+
+/// Hold `event` back until the prompt reading keys is done
+pub fn defer(event: Event) {
+    DEFERRED.lock().unwrap().push(event);
+}
+
+/// Queue the events prompts held back again
+pub fn resend_deferred() {
+    for event in std::mem::take(&mut *DEFERRED.lock().unwrap()) {
+        send(event);
+    }
+}
+
+/// Read the terminal on a background thread. Nothing else may read it
+/// directly afterwards; prompts wait on the channel like the main loop.
+pub fn spawn_input_thread() {
+    std::thread::spawn(|| loop {
+        send(Event::Input(get_event()));
+    });
+}
@@ -0,0 +1,83 @@
+use std::ops::Range;
+
+use ropey::Rope;
+use syntect::{
+    highlighting::{HighlightIterator, HighlightState, Highlighter, Style},
+    parsing::{ParseState, ScopeStack, SyntaxReference},
+};
+
+use crate::SynHighlighter;
+
+// keep parser state for every Nth line, a balance between memory and how far
+// back rendering has to start highlighting
+const CHECKPOINT_INTERVAL: usize = 16;
+
+/// Parser and highlighter state at the start of every `CHECKPOINT_INTERVAL`th
+/// line, so constructs spanning lines (block comments, strings) highlight
+/// correctly without re-parsing the buffer from the top every frame.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HighlightCache {
+    syntax: String, // name of the syntax the checkpoints were made with
+    checkpoints: Vec<(ParseState, HighlightState)>,
+}
+
+impl HighlightCache {
+    /// Drop the checkpoints after `row`, which was edited
+    pub fn invalidate(&mut self, row: usize) {
+        self.checkpoints.truncate(row / CHECKPOINT_INTERVAL + 1);
+    }
+
+    /// Styled spans of each full line in `rows`
+    pub fn highlight(
+        &mut self,
+        rope: &Rope,
+        rows: Range<usize>,
+        syntax: &SyntaxReference,
+        sh: &SynHighlighter,
+    ) -> Vec<Vec<(Style, String)>> {
+        let highlighter = Highlighter::new(&sh.theme_set.themes[sh.theme.as_str()]);
+
+        if self.syntax != syntax.name || self.checkpoints.is_empty() {
+            self.syntax = syntax.name.clone();
+            self.checkpoints = vec![(
+                ParseState::new(syntax),
+                HighlightState::new(&highlighter, ScopeStack::new()),
+            )];
+        }
+
+        let checkpoint = (rows.start / CHECKPOINT_INTERVAL).min(self.checkpoints.len() - 1);
+        let (mut parse_state, mut highlight_state) = self.checkpoints[checkpoint].clone();
+
+        let mut lines = Vec::with_capacity(rows.len());
+        for row in checkpoint * CHECKPOINT_INTERVAL..rows.end {
+            if row % CHECKPOINT_INTERVAL == 0 && row / CHECKPOINT_INTERVAL == self.checkpoints.len()
+            {
+                self.checkpoints
+                    .push((parse_state.clone(), highlight_state.clone()));
+            }
+
+            // the newline syntax set expects lines with their line break
+            let mut line = rope.line(row).to_string();
+            if !line.ends_with('\n') {
+                line.push('\n');
+            }
+
+            let ops = parse_state
+                .parse_line(&line, &sh.syntax_set)
+                .unwrap_or_default();
+            let spans = HighlightIterator::new(&mut highlight_state, &ops, &line, &highlighter);
+
+            if rows.contains(&row) {
+                lines.push(
+                    spans
+                        .map(|(style, text)| (style, text.trim_end_matches('\n').to_string()))
+                        .collect(),
+                );
+            } else {
+                spans.for_each(drop);
+            }
+        }
+
+        lines
+    }
+}
@@ -0,0 +1,280 @@
+use ropey::Rope;
+
+#[derive(Debug, Clone, PartialEq)]
+enum EditKind {
+    Insert,
+    Remove,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Edit {
+    kind: EditKind,
+    char_idx: usize,
+    text: String,
+}
+
+impl Edit {
+    fn apply(&self, rope: &mut Rope) {
+        match self.kind {
+            EditKind::Insert => rope.insert(self.char_idx, &self.text),
+            EditKind::Remove => {
+                rope.remove(self.char_idx..self.char_idx + self.text.chars().count())
+            }
+        }
+    }
+
+    fn revert(&self, rope: &mut Rope) {
+        match self.kind {
+            EditKind::Insert => {
+                rope.remove(self.char_idx..self.char_idx + self.text.chars().count())
+            }
+            EditKind::Remove => rope.insert(self.char_idx, &self.text),
+        }
+    }
+}
+
+/// A group of edits that is undone/redone as a single step
+#[derive(Debug, Clone, PartialEq)]
+struct Transaction {
+    edits: Vec<Edit>,
+    cursor_before: (usize, usize),
+    cursor_after: (usize, usize),
+    typed: bool,
+}
+
+impl Transaction {
+    fn first_changed(&self) -> usize {
+        self.edits
+            .iter()
+            .map(|edit| edit.char_idx)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Whether `next` continues typing right where this transaction left off
+    fn continues_with(&self, next: &Transaction) -> bool {
+        let (Some(last), [edit]) = (self.edits.last(), next.edits.as_slice()) else {
+            return false;
+        };
+
+        self.typed
+            && next.typed
+            && last.kind == EditKind::Insert
+            && edit.kind == EditKind::Insert
+            && last.char_idx + last.text.chars().count() == edit.char_idx
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    undo_stack: Vec<Transaction>,
+    redo_stack: Vec<Transaction>,
+    pending: Option<Transaction>,
+    saved: Option<usize>, // undo steps in the saved text, None once an edit replaced them
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            pending: None,
+            saved: Some(0),
+        }
+    }
+}
+
+impl History {
+    pub fn record_insert(&mut self, char_idx: usize, text: &str, cursor: (usize, usize)) {
+        self.record(EditKind::Insert, char_idx, text, cursor);
+    }
+
+    pub fn record_remove(&mut self, char_idx: usize, text: &str, cursor: (usize, usize)) {
+        self.record(EditKind::Remove, char_idx, text, cursor);
+    }
+
+    fn record(&mut self, kind: EditKind, char_idx: usize, text: &str, cursor: (usize, usize)) {
+        if text.is_empty() {
+            return;
+        }
+
+        self.redo_stack.clear();
+        if self
+            .saved
+            .is_some_and(|saved| saved > self.undo_stack.len())
+        {
+            self.saved = None;
+        }
+        self.pending
+            .get_or_insert(Transaction {
+                edits: Vec::new(),
+                cursor_before: cursor,
+                cursor_after: cursor,
+                typed: false,
+            })
+            .edits
+            .push(Edit {
+                kind,
+                char_idx,
+                text: text.to_string(),
+            });
+    }
+
+    /// Close the pending transaction. Consecutive typed characters are merged
+    /// into one undo step; anything else in between, like moving the cursor,
+    /// ends the run.
+    pub fn commit(&mut self, cursor: (usize, usize), typed: bool) {
+        let Some(mut transaction) = self.pending.take() else {
+            if !typed {
+                self.end_typing();
+            }
+            return;
+        };
+        transaction.cursor_after = cursor;
+        transaction.typed = typed;
+
+        match self.undo_stack.last_mut() {
+            Some(last) if last.continues_with(&transaction) => {
+                let edit = transaction.edits.pop().unwrap();
+                last.edits.last_mut().unwrap().text.push_str(&edit.text);
+                last.cursor_after = transaction.cursor_after;
+            }
+            _ => self.undo_stack.push(transaction),
+        }
+    }
+
+    fn end_typing(&mut self) {
+        if let Some(last) = self.undo_stack.last_mut() {
+            last.typed = false;
+        }
+    }
+
+    /// Remember the current state as the one in the file
+    pub fn mark_saved(&mut self) {
+        self.end_typing();
+        self.saved = Some(self.undo_stack.len());
+    }
+
+    /// Whether undo and redo have brought the text back to what was saved
+    pub fn is_saved(&self) -> bool {
+        self.pending.is_none() && self.saved == Some(self.undo_stack.len())
+    }
+
+    /// Revert the last transaction, returning the cursor position before it
+    /// and the first char index that changed
+    pub fn undo(
+        &mut self,
+        rope: &mut Rope,
+        cursor: (usize, usize),
+    ) -> Option<((usize, usize), usize)> {
+        self.commit(cursor, false);
+        let transaction = self.undo_stack.pop()?;
+
+        for edit in transaction.edits.iter().rev() {
+            edit.revert(rope);
+        }
+
+        let result = (transaction.cursor_before, transaction.first_changed());
+        self.redo_stack.push(transaction);
+        Some(result)
+    }
+
+    /// Reapply the last undone transaction, returning the cursor position after
+    /// it and the first char index that changed
+    pub fn redo(&mut self, rope: &mut Rope) -> Option<((usize, usize), usize)> {
+        let transaction = self.redo_stack.pop()?;
+
+        for edit in &transaction.edits {
+            edit.apply(rope);
+        }
+
+        let result = (transaction.cursor_after, transaction.first_changed());
+        self.undo_stack.push(transaction);
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // type `text` at char index `idx` of `rope` one character at a time
+    fn type_text(history: &mut History, rope: &mut Rope, idx: usize, text: &str) {
+        for (offset, c) in text.chars().enumerate() {
+            let s = c.to_string();
+            history.record_insert(idx + offset, &s, (idx + offset, 0));
+            rope.insert(idx + offset, &s);
+            history.commit((idx + offset + 1, 0), true);
+        }
+    }
+
+    #[test]
+    fn typing_is_one_step() {
+        let mut history = History::default();
+        let mut rope = Rope::new();
+        type_text(&mut history, &mut rope, 0, "hello");
+
+        // a removal starts a step of its own
+        history.record_remove(4, "o", (5, 0));
+        rope.remove(4..5);
+        history.commit((4, 0), false);
+
+        assert_eq!(history.undo(&mut rope, (4, 0)), Some(((5, 0), 4)));
+        assert_eq!(rope.to_string(), "hello");
+        assert_eq!(history.undo(&mut rope, (5, 0)), Some(((0, 0), 0)));
+        assert_eq!(rope.to_string(), "");
+        assert_eq!(history.undo(&mut rope, (0, 0)), None);
+
+        assert_eq!(history.redo(&mut rope), Some(((5, 0), 0)));
+        assert_eq!(rope.to_string(), "hello");
+    }
+
+    #[test]
+    fn new_edit_clears_redo() {
+        let mut history = History::default();
+        let mut rope = Rope::new();
+        type_text(&mut history, &mut rope, 0, "ab");
+        history.undo(&mut rope, (2, 0));
+        assert_eq!(rope.to_string(), "");
+
+        type_text(&mut history, &mut rope, 0, "c");
+        assert_eq!(history.redo(&mut rope), None);
+        assert_eq!(rope.to_string(), "c");
+    }
+
+    #[test]
+    fn moving_ends_typing() {
+        let mut history = History::default();
+        let mut rope = Rope::new();
+        type_text(&mut history, &mut rope, 0, "ab");
+        history.commit((1, 0), false);
+        history.commit((2, 0), false);
+        type_text(&mut history, &mut rope, 2, "c");
+
+        history.undo(&mut rope, (3, 0));
+        assert_eq!(rope.to_string(), "ab");
+    }
+
+    #[test]
+    fn undo_back_to_saved() {
+        let mut history = History::default();
+        let mut rope = Rope::new();
+        type_text(&mut history, &mut rope, 0, "ab");
+        history.mark_saved();
+        type_text(&mut history, &mut rope, 2, "c");
+        assert!(!history.is_saved());
+
+        history.undo(&mut rope, (3, 0));
+        assert!(history.is_saved());
+        history.undo(&mut rope, (2, 0));
+        assert!(!history.is_saved());
+        history.redo(&mut rope);
+        assert!(history.is_saved());
+
+        // the saved state can't be reached again after a different edit
+        history.undo(&mut rope, (2, 0));
+        type_text(&mut history, &mut rope, 0, "x");
+        history.undo(&mut rope, (1, 0));
+        assert!(!history.is_saved());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::textbuf::{grapheme_width, TextBuf};
+
+/// How a language's code is indented
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndentRules {
+    pub openers: &'static [&'static str], // a line ending in one of these indents the next
+    pub closers: &'static [&'static str], // typed on a line of their own, these dedent it
+}
+
+const BRACKETS: IndentRules = IndentRules {
+    openers: &["{", "(", "["],
+    closers: &["}", ")", "]"],
+};
+
+const NONE: IndentRules = IndentRules {
+    openers: &[],
+    closers: &[],
+};
+
+/// Rules for the language named `syntax`. Languages without their own are
+/// taken to use brackets, like C; prose only keeps the indentation.
+pub fn rules(syntax: &str) -> IndentRules {
+    match syntax {
+        "Python" => IndentRules {
+            openers: &[":", "{", "(", "["],
+            closers: &["}", ")", "]"],
+        },
+        "YAML" => IndentRules {
+            openers: &[":"],
+            closers: &[],
+        },
+        "Bourne Again Shell (bash)" | "Lua" | "Ruby" => IndentRules {
+            openers: &["then", "do", "else", "{", "("],
+            closers: &[
+                "fi", "done", "end", "else", "elif", "elsif", "elseif", "}", ")",
+            ],
+        },
+        "Plain Text" | "Markdown" | "reStructuredText" | "LaTeX" | "HTML" | "XML" => NONE,
+        _ => BRACKETS,
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl IndentRules {
+    /// Whether a line ending in `text` opens a block. Word openers have to be
+    /// whole words.
+    fn opens(&self, text: &str) -> bool {
+        let text = text.trim_end();
+        self.openers.iter().any(|opener| {
+            text.strip_suffix(opener).is_some_and(|before| {
+                !opener.starts_with(is_word_char) || !before.ends_with(is_word_char)
+            })
+        })
+    }
+
+    /// Whether `text` starts with a closer, a whole word for word closers
+    fn closes(&self, text: &str) -> bool {
+        let text = text.trim_start();
+        self.closers.iter().any(|closer| {
+            text.strip_prefix(closer).is_some_and(|after| {
+                !closer.starts_with(is_word_char) || !after.starts_with(is_word_char)
+            })
+        })
+    }
+}
+
+/// What one level of indentation is in this buffer
+pub fn unit(textbuf: &TextBuf) -> String {
+    if textbuf.insert_spaces {
+        " ".repeat(textbuf.indent_width)
+    } else {
+        "\t".to_string()
+    }
+}
+
+/// Leading whitespace of `row`, up to column `end`
+pub fn leading(textbuf: &TextBuf, row: usize, end: usize) -> String {
+    textbuf
+        .line(row)
+        .chars()
+        .take(end)
+        .take_while(|&c| c == ' ' || c == '\t')
+        .collect()
+}
+
+/// Break the line at the cursor, starting the new line as indented as this
+/// one, or a level more after an opener. Between an opener and its closer,
+/// the closer goes on a line of its own.
+pub fn insert_newline(textbuf: &mut TextBuf, rules: IndentRules) {
+    let (col, row) = textbuf.cursor;
+    let indent = leading(textbuf, row, col);
+    let line = textbuf.line(row).to_string();
+    let (before, after) = line.split_at(
+        line.char_indices()
+            .nth(col)
+            .map_or(line.len(), |(idx, _)| idx),
+    );
+
+    if !rules.opens(before) {
+        textbuf.insert_at_cursor(&format!("\n{indent}"));
+        return;
+    }
+
+    let closes = rules.closes(after);
+    if closes {
+        // the closer's new line starts with the indentation, not these spaces
+        let spaces = after.chars().take_while(|c| c.is_whitespace()).count();
+        textbuf.remove((col, row), (col + spaces, row));
+    }
+
+    let inner = format!("\n{indent}{}", unit(textbuf));
+    textbuf.insert_at_cursor(&inner);
+    if closes {
+        let cursor = textbuf.cursor;
+        textbuf.insert_at_cursor(&format!("\n{indent}"));
+        textbuf.cursor = cursor;
+    }
+}
+
+/// Column to delete back to from the end of the whitespace `leading`, to
+/// take one level of indentation off
+pub fn dedent_start(textbuf: &TextBuf, leading: &str) -> usize {
+    let chars: Vec<char> = leading.chars().collect();
+    if chars.last() == Some(&'\t') {
+        return chars.len() - 1;
+    }
+
+    let mut width = 0;
+    for c in &chars {
+        width += grapheme_width(c.encode_utf8(&mut [0; 4]), width, textbuf.tab_width);
+    }
+    let indent_width = textbuf.indent_width.max(1);
+    let stop = width.saturating_sub(1) / indent_width * indent_width;
+
+    let mut start = chars.len();
+    while start > 0 && chars[start - 1] == ' ' && width > stop {
+        start -= 1;
+        width -= 1;
+    }
+    start
+}
+
+/// After typing, take a level of indentation off a line that has become
+/// just a closer, like `}` or `fi`. Lines already less indented than the one
+/// above are left alone, so `else` growing into `elseif` only dedents once.
+pub fn dedent_closer(textbuf: &mut TextBuf, rules: IndentRules) {
+    let (col, row) = textbuf.cursor;
+    let indent = leading(textbuf, row, col);
+    let indent_len = indent.chars().count();
+    let line = textbuf.line(row).to_string();
+    let typed: String = line.chars().take(col).skip(indent_len).collect();
+    let rest: String = line.chars().skip(col).collect();
+    if indent.is_empty() || !rules.closers.contains(&typed.as_str()) || !rest.trim().is_empty() {
+        return;
+    }
+
+    let above = (0..row)
+        .rev()
+        .find(|&above| textbuf.line(above).chars().any(|c| !c.is_whitespace()));
+    if let Some(above) = above {
+        let above_indent = leading(textbuf, above, usize::MAX).chars().count();
+        if textbuf.visual_col((indent_len, row)) < textbuf.visual_col((above_indent, above)) {
+            return;
+        }
+    }
+
+    let start = dedent_start(textbuf, &indent);
+    textbuf.remove((start, row), (indent_len, row));
+    textbuf.cursor.0 = col - (indent_len - start);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn textbuf(text: &str, cursor: (usize, usize)) -> TextBuf {
+        let mut textbuf = TextBuf::new();
+        textbuf.append(text);
+        textbuf.cursor = cursor;
+        textbuf.indent_width = 4;
+        textbuf
+    }
+
+    #[test]
+    fn newline_keeps_and_adds_indentation() {
+        let rust = rules("Rust");
+
+        let mut buf = textbuf("    let x = 1;", (14, 0));
+        insert_newline(&mut buf, rust);
+        assert_eq!(buf.rope.to_string(), "    let x = 1;\n    ");
+        assert_eq!(buf.cursor, (4, 1));
+
+        let mut buf = textbuf("fn main() {}", (11, 0));
+        insert_newline(&mut buf, rust);
+        assert_eq!(buf.rope.to_string(), "fn main() {\n    \n}");
+        assert_eq!(buf.cursor, (4, 1));
+
+        let mut buf = textbuf("if x:", (5, 0));
+        insert_newline(&mut buf, rules("Python"));
+        assert_eq!(buf.cursor, (4, 1));
+
+        let mut buf = textbuf("foo do end", (6, 0));
+        insert_newline(&mut buf, rules("Ruby"));
+        assert_eq!(buf.rope.to_string(), "foo do\n    \nend");
+
+        let mut buf = textbuf("undo", (4, 0));
+        insert_newline(&mut buf, rules("Ruby"));
+        assert_eq!(buf.cursor, (0, 1));
+    }
+
+    #[test]
+    fn closers_and_backspace_dedent() {
+        let mut buf = textbuf("    x\n        }", (9, 1));
+        dedent_closer(&mut buf, rules("Rust"));
+        assert_eq!(buf.rope.to_string(), "    x\n    }");
+        assert_eq!(buf.cursor, (5, 1));
+
+        let bash = rules("Bourne Again Shell (bash)");
+        let mut buf = textbuf("if x; then\n    fi", (6, 1));
+        dedent_closer(&mut buf, bash);
+        assert_eq!(buf.rope.to_string(), "if x; then\nfi");
+        assert_eq!(buf.cursor, (2, 1));
+
+        // only whole lines, and only once
+        let mut buf = textbuf("foo do\n    x = fi", (10, 1));
+        dedent_closer(&mut buf, bash);
+        assert_eq!(buf.cursor, (10, 1));
+        let mut buf = textbuf("if x then\n    y\nelseif", (6, 2));
+        dedent_closer(&mut buf, rules("Lua"));
+        assert_eq!(buf.cursor, (6, 2));
+
+        let buf = textbuf("", (0, 0));
+        assert_eq!(dedent_start(&buf, "      "), 4);
+        assert_eq!(dedent_start(&buf, "    "), 0);
+        assert_eq!(dedent_start(&buf, "\t\t"), 1);
+    }
+}
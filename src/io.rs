@@ -1,17 +1,28 @@
 use crossterm::{
-    self, cursor,
-    event::{KeyCode, KeyModifiers, MouseEvent, MouseEventKind},
-    queue,
-    style::{Color, Stylize},
+    self,
+    event::{KeyCode, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
+    style::{Color, ContentStyle, Stylize},
 };
-use std::{
-    cmp::min,
-    io::{ErrorKind, Stdout, Write},
+use std::{cell::RefCell, cmp::min, collections::VecDeque, io::ErrorKind, sync::Arc};
+
+use crate::{
+    clipboard::Clipboard,
+    editor::Editor,
+    encoding::Encoding,
+    events::{self, Event},
+    indent::{self, IndentRules},
+    keyboard,
+    keymap::{format_sequence, Action, Chord, Lookup, Motion},
+    screen::{Grid, Screen},
+    search::Search,
+    textbuf::{grapheme_width, graphemes, LineNumbers},
+    window::Split,
+    SynHighlighter, TextBuf,
 };
 
-use crate::{SynHighlighter, TextBuf, TABLENGTH};
-
+use regex::Regex;
 use syntect::highlighting::Color as SyntectColor;
+use unicode_width::UnicodeWidthStr;
 
 pub struct KeyStroke(pub KeyCode, KeyModifiers);
 
@@ -20,24 +31,47 @@ pub enum InputEvent {
     KeyStroke(KeyCode, KeyModifiers),
     Mouse(MouseEvent),
     Resize(u16, u16),
+    Paste(String),
+}
+
+thread_local! {
+    // characters of a paste into a prompt, not yet read
+    static PASTED: RefCell<VecDeque<char>> = RefCell::default();
 }
 
+/// Wait for a key press. Text pasted into a prompt is read as typed, with line
+/// breaks as spaces, and other events are held back for the main loop.
 pub fn get_key() -> KeyStroke {
     loop {
-        if let crossterm::event::Event::Key(event) = crossterm::event::read().unwrap() {
-            if event.kind == crossterm::event::KeyEventKind::Press {
-                return KeyStroke(event.code, event.modifiers);
+        if let Some(c) = PASTED.with_borrow_mut(VecDeque::pop_front) {
+            return KeyStroke(KeyCode::Char(c), KeyModifiers::NONE);
+        }
+
+        match events::next(None) {
+            Event::Input(InputEvent::KeyStroke(code, modifiers)) => {
+                return KeyStroke(code, modifiers);
             }
+            Event::Input(InputEvent::Paste(text)) => PASTED.with_borrow_mut(|pasted| {
+                pasted.extend(text.chars().map(|c| if c.is_control() { ' ' } else { c }));
+            }),
+            event => events::defer(event),
         }
     }
 }
 
+/// Once prompts are done: drop what was pasted into them but not read, and
+/// hand the events they held back to the main loop
+pub fn end_prompts() {
+    PASTED.with_borrow_mut(VecDeque::clear);
+    events::resend_deferred();
+}
+
 pub fn get_event() -> InputEvent {
     loop {
         match crossterm::event::read().unwrap() {
             crossterm::event::Event::Key(event) => {
-                if event.kind == crossterm::event::KeyEventKind::Press {
-                    return InputEvent::KeyStroke(event.code, event.modifiers);
+                if let Some((code, modifiers)) = keyboard::normalize(event) {
+                    return InputEvent::KeyStroke(code, modifiers);
                 }
             }
             crossterm::event::Event::Mouse(event) => {
@@ -46,44 +80,214 @@ pub fn get_event() -> InputEvent {
             crossterm::event::Event::Resize(width, height) => {
                 return InputEvent::Resize(width, height);
             }
+            crossterm::event::Event::Paste(text) => {
+                return InputEvent::Paste(text);
+            }
             _ => {}
         }
     }
 }
 
-pub fn nonblocking_get_event() -> Option<InputEvent> {
-    match crossterm::event::poll(std::time::Duration::from_millis(0)).unwrap() {
-        true => Some(get_event()),
-        false => None,
+pub fn process_event(event: InputEvent, editor: &mut Editor, sh: &SynHighlighter) {
+    match &event {
+        InputEvent::KeyStroke(key, modifiers) => {
+            process_key(Chord(*key, *modifiers), editor, sh);
+            return;
+        }
+        InputEvent::Mouse(mouse_event) => {
+            // clicks and scrolling go to the pane under the mouse, drags stay
+            // in the pane they started in
+            if !matches!(
+                mouse_event.kind,
+                MouseEventKind::Drag(_) | MouseEventKind::Up(_) | MouseEventKind::Moved
+            ) {
+                let position = (mouse_event.column, mouse_event.row);
+                match editor
+                    .layout
+                    .panes(editor.area)
+                    .iter()
+                    .position(|(_, rect)| rect.contains(position))
+                {
+                    Some(idx) => editor.focus_pane(idx),
+                    None => return,
+                }
+            }
+        }
+        InputEvent::Resize(width, height) => {
+            editor.resize(*width, *height);
+            return;
+        }
+        InputEvent::Paste(_) => {}
     }
-}
 
-pub fn process_event(event: InputEvent, textbuf: &mut TextBuf) {
+    let buffer = Arc::clone(editor.current());
+    let textbuf = &mut buffer.lock().unwrap();
+
     match event {
-        InputEvent::KeyStroke(key, modifiers) => {
-            process_key_code(KeyStroke(key, modifiers), textbuf);
-        }
+        InputEvent::KeyStroke(..) | InputEvent::Resize(..) => {} // handled above
         InputEvent::Mouse(mouse_event) => {
             process_mouse_code(mouse_event, textbuf);
+            textbuf.commit(false);
         }
-        InputEvent::Resize(width, height) => {
-            textbuf.dimensions = (width, height);
+        InputEvent::Paste(_) if textbuf.read_only_reason().is_some() => {
+            let reason = textbuf.read_only_reason().unwrap_or_default();
+            textbuf.set_message(reason);
+        }
+        InputEvent::Paste(text) => {
+            // terminals send line breaks in pastes as carriage returns
+            let text = text.replace("\r\n", "\n").replace('\r', "\n");
+
+            textbuf.delete_selection();
+            textbuf.insert_at_cursor(&text);
+            textbuf.commit(false);
+
+            textbuf.save_changed = true;
+            textbuf.dirty = true;
+        }
+    }
+}
+
+/// Look `key` up in the keymap, after the start of a key sequence if one was
+/// typed, and do what the keys are bound to. Characters without a binding are
+/// inserted.
+fn process_key(key: Chord, editor: &mut Editor, sh: &SynHighlighter) {
+    editor.pending_keys.push(key);
+    let action = match editor.keymap.lookup(&editor.pending_keys) {
+        Lookup::Action(action) => Some(action),
+        Lookup::Prefix => {
+            let typed = format_sequence(&editor.pending_keys);
+            editor
+                .current()
+                .lock()
+                .unwrap()
+                .set_message(format!("{typed} -"));
+            return;
+        }
+        Lookup::Unbound => match editor.pending_keys[..] {
+            [Chord(KeyCode::Char(c), KeyModifiers::NONE)] => Some(Action::InsertChar(c)),
+            [_] => None,
+            _ => {
+                let typed = format_sequence(&editor.pending_keys);
+                editor
+                    .current()
+                    .lock()
+                    .unwrap()
+                    .set_message(format!("{typed} is not bound"));
+                None
+            }
+        },
+    };
+
+    let typed = std::mem::take(&mut editor.pending_keys);
+    let Some(action) = action else {
+        return;
+    };
+    if typed.len() > 1 {
+        // the prompt for the rest of the sequence
+        let mut textbuf = editor.current().lock().unwrap();
+        textbuf.message = None;
+        textbuf.dirty = true;
+    }
+
+    if process_editor_action(action, editor, sh) {
+        return;
+    }
+
+    let buffer = Arc::clone(editor.current());
+    let textbuf = &mut buffer.lock().unwrap();
+    if let Some(reason) = textbuf.read_only_reason() {
+        if action.is_edit() {
+            textbuf.set_message(reason);
+            return;
+        }
+    }
+
+    process_action(
+        action,
+        textbuf,
+        &mut editor.clipboard,
+        &mut editor.screen,
+        sh,
+    );
+    textbuf.commit(matches!(action, Action::InsertChar(_)));
+}
+
+/// Actions on the editor rather than the current buffer. Returns whether the
+/// action was handled.
+fn process_editor_action(action: Action, editor: &mut Editor, sh: &SynHighlighter) -> bool {
+    match action {
+        Action::NextBuffer => editor.next_buffer(),
+        Action::PreviousBuffer => editor.previous_buffer(),
+
+        Action::PickBuffer => {
+            if let Some(idx) = buffer_picker(editor) {
+                editor.switch_to(idx);
+            }
+            editor.current().lock().unwrap().dirty = true;
         }
+
+        Action::SplitHorizontal | Action::SplitVertical => {
+            let split = match action {
+                Action::SplitHorizontal => Split::Horizontal,
+                _ => Split::Vertical,
+            };
+            if !editor.split(split) {
+                editor
+                    .current()
+                    .lock()
+                    .unwrap()
+                    .set_message("Pane too small to split");
+            }
+        }
+
+        Action::ClosePane => {
+            if !editor.close_pane() {
+                editor
+                    .current()
+                    .lock()
+                    .unwrap()
+                    .set_message("Can't close the last pane");
+            }
+        }
+
+        Action::Focus(direction) => editor.focus_direction(direction),
+        Action::ResizePane(direction) => editor.resize_pane(direction),
+
+        Action::OpenFile => {
+            let buffer = Arc::clone(editor.current());
+            render_textbuf(&mut buffer.lock().unwrap(), &mut editor.screen, sh);
+
+            match input_prompt("Open file: ", &mut editor.screen) {
+                Some(filename) if !filename.is_empty() => editor.open(Some(filename), sh),
+                _ => buffer.lock().unwrap().dirty = true,
+            }
+        }
+
+        Action::Quit => {
+            if quit_prompt(editor, sh).is_ok() {
+                editor.quit = true;
+            }
+        }
+        Action::ForceQuit => editor.quit = true,
+
+        _ => return false,
     }
+
+    true
 }
 
 fn process_mouse_code(event: MouseEvent, textbuf: &mut TextBuf) {
     match event.kind {
         MouseEventKind::ScrollDown => {
-            if textbuf.row_buffer.len() > textbuf.dimensions.1 as usize
-                && textbuf.viewport_v_offset
-                    < textbuf.row_buffer.len() - textbuf.dimensions.1 as usize
+            if textbuf.len_lines() > textbuf.text_rows()
+                && textbuf.viewport_v_offset < textbuf.len_lines() - textbuf.text_rows()
             {
                 textbuf.viewport_v_offset += 1;
             }
 
             if textbuf.cursor.1 <= textbuf.viewport_v_offset {
                 textbuf.cursor.1 = textbuf.viewport_v_offset;
+                textbuf.clamp_cursor();
             }
 
             textbuf.dirty = true;
@@ -94,28 +298,23 @@ fn process_mouse_code(event: MouseEvent, textbuf: &mut TextBuf) {
                 textbuf.viewport_v_offset -= 1;
             }
 
-            if textbuf.cursor.1 >= textbuf.viewport_v_offset + textbuf.dimensions.1 as usize - 1 {
-                textbuf.cursor.1 = textbuf.viewport_v_offset + textbuf.dimensions.1 as usize - 1;
+            if textbuf.cursor.1 >= textbuf.viewport_v_offset + textbuf.text_rows() - 1 {
+                textbuf.cursor.1 = textbuf.viewport_v_offset + textbuf.text_rows() - 1;
+                textbuf.clamp_cursor();
             }
 
             textbuf.dirty = true;
         }
 
         MouseEventKind::Down(_) => {
-            let mut x = event.column as usize + textbuf.viewport_h_offset;
-            let mut y = event.row as usize + textbuf.viewport_v_offset;
-
-            if !textbuf.row_buffer.is_empty() {
-                if y > textbuf.row_buffer.len() - 1 {
-                    y = textbuf.row_buffer.len() - 1;
-                }
+            textbuf.cursor = mouse_position(&event, textbuf);
+            textbuf.selection_anchor = Some(textbuf.cursor);
 
-                if x > textbuf.row_buffer[y].len() {
-                    x = textbuf.row_buffer[y].len();
-                }
+            textbuf.dirty = true;
+        }
 
-                textbuf.cursor = (x, y);
-            }
+        MouseEventKind::Drag(MouseButton::Left) => {
+            textbuf.cursor = mouse_position(&event, textbuf);
 
             textbuf.dirty = true;
         }
@@ -124,282 +323,671 @@ fn process_mouse_code(event: MouseEvent, textbuf: &mut TextBuf) {
     }
 }
 
-fn process_key_code(key: KeyStroke, textbuf: &mut TextBuf) {
-    match key {
-        KeyStroke(KeyCode::Char(c), KeyModifiers::NONE) => {
-            if textbuf.cursor.1 >= textbuf.row_buffer.len() {
-                textbuf.row_buffer.push(Vec::new());
-            }
+/// Buffer position under the mouse pointer, clamped to the text
+fn mouse_position(event: &MouseEvent, textbuf: &TextBuf) -> (usize, usize) {
+    let column = event.column.saturating_sub(textbuf.origin.0) as usize;
+    let row = event.row.saturating_sub(textbuf.origin.1) as usize;
 
-            if textbuf.cursor.0 >= textbuf.row_buffer[textbuf.cursor.1].len() {
-                textbuf.row_buffer[textbuf.cursor.1].push(c);
-            } else {
-                textbuf.row_buffer[textbuf.cursor.1].insert(textbuf.cursor.0, c);
-            }
+    textbuf.position_at_view(
+        min(row, textbuf.text_rows() - 1),
+        column.saturating_sub(textbuf.gutter_width()),
+    )
+}
+
+/// Span of `row` including a line break, used to cut or copy the whole line
+fn line_span(textbuf: &TextBuf, row: usize) -> ((usize, usize), (usize, usize)) {
+    if row + 1 < textbuf.len_lines() {
+        ((0, row), (0, row + 1))
+    } else if row > 0 {
+        (
+            (textbuf.line_len(row - 1), row - 1),
+            (textbuf.line_len(row), row),
+        )
+    } else {
+        ((0, row), (textbuf.line_len(row), row))
+    }
+}
+
+fn process_action(
+    action: Action,
+    textbuf: &mut TextBuf,
+    clipboard: &mut Clipboard,
+    screen: &mut Screen,
+    sh: &SynHighlighter,
+) {
+    let last_yank = clipboard.last_yank.take();
+
+    // selecting movement extends the selection, plain movement drops it
+    match action {
+        Action::Select(_) => {
+            textbuf.selection_anchor.get_or_insert(textbuf.cursor);
+        }
+        Action::Move(_) => textbuf.selection_anchor = None,
+        _ => {}
+    }
 
+    match action {
+        Action::InsertChar(c) => {
+            textbuf.delete_selection();
+            textbuf.insert_char(textbuf.cursor, c);
             textbuf.cursor.0 += 1;
+            indent::dedent_closer(textbuf, indent_rules(textbuf, sh));
             textbuf.save_changed = true;
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Backspace, _) => {
-            if textbuf.cursor.0 > 0 {
-                textbuf.cursor.0 -= 1;
-                textbuf.row_buffer[textbuf.cursor.1].remove(textbuf.cursor.0);
+        Action::Move(motion) | Action::Select(motion) => {
+            move_cursor(textbuf, motion);
+            textbuf.dirty = true;
+        }
+
+        Action::DeleteBackward => {
+            if textbuf.delete_selection() {
+                // the selection was the thing to delete
+            } else if textbuf.cursor.0 > 0 && in_leading_whitespace(textbuf) {
+                // a level of indentation
+                let leading = indent::leading(textbuf, textbuf.cursor.1, textbuf.cursor.0);
+                let start = (indent::dedent_start(textbuf, &leading), textbuf.cursor.1);
+                textbuf.remove(start, textbuf.cursor);
+                textbuf.cursor = start;
+            } else if textbuf.cursor.0 > 0 {
+                // the whole grapheme, with any combining marks
+                let start = (textbuf.prev_grapheme(textbuf.cursor), textbuf.cursor.1);
+                textbuf.remove(start, textbuf.cursor);
+                textbuf.cursor = start;
             } else if textbuf.cursor.1 > 0 {
-                if textbuf.row_buffer[textbuf.cursor.1].is_empty() {
-                    textbuf.row_buffer.remove(textbuf.cursor.1);
-                } else {
-                    textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1 - 1].len();
-                    let row = textbuf.row_buffer.remove(textbuf.cursor.1).into_iter();
-                    textbuf.row_buffer[textbuf.cursor.1 - 1].extend(row);
-                }
-                textbuf.cursor.1 -= 1;
-                textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1].len();
+                let prev = (textbuf.line_len(textbuf.cursor.1 - 1), textbuf.cursor.1 - 1);
+                textbuf.remove(prev, textbuf.cursor);
+                textbuf.cursor = prev;
             }
 
             textbuf.save_changed = true;
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Up, _) => {
-            if textbuf.cursor.1 > 0 {
-                textbuf.cursor.1 -= 1;
-                if textbuf.cursor.0 > textbuf.row_buffer[textbuf.cursor.1].len() {
-                    textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1].len();
-                }
-            } else {
-                textbuf.cursor.0 = 0;
-            }
+        Action::InsertNewline => {
+            textbuf.delete_selection();
+            indent::insert_newline(textbuf, indent_rules(textbuf, sh));
 
+            textbuf.save_changed = true;
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Down, _) => {
-            if textbuf.cursor.1 < textbuf.row_buffer.len() - 1 {
-                textbuf.cursor.1 += 1;
-                if textbuf.cursor.0 > textbuf.row_buffer[textbuf.cursor.1].len() {
-                    textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1].len();
-                }
-            } else {
-                textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1].len();
-            }
+        Action::InsertTab => {
+            textbuf.delete_selection();
+            textbuf.insert_at_cursor(&indent::unit(textbuf));
 
+            textbuf.save_changed = true;
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Left, _) => {
-            if textbuf.cursor.0 > 0 {
-                textbuf.cursor.0 -= 1;
-            } else if textbuf.cursor.1 > 0 {
-                textbuf.cursor.1 -= 1;
-                textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1].len();
+        Action::DeleteForward => {
+            if textbuf.delete_selection() {
+                // the selection was the thing to delete
+            } else if textbuf.cursor.0 < textbuf.line_len(textbuf.cursor.1) {
+                let end = (textbuf.next_grapheme(textbuf.cursor), textbuf.cursor.1);
+                textbuf.remove(textbuf.cursor, end);
+            } else if textbuf.cursor.1 < textbuf.len_lines() - 1 {
+                textbuf.remove(textbuf.cursor, (0, textbuf.cursor.1 + 1));
             }
 
+            textbuf.save_changed = true;
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Right, _) => {
-            if textbuf.cursor.0 < textbuf.row_buffer[textbuf.cursor.1].len() {
-                textbuf.cursor.0 += 1;
-            } else if textbuf.cursor.1 < textbuf.row_buffer.len() - 1 {
-                textbuf.cursor.1 += 1;
-                textbuf.cursor.0 = 0;
+        Action::Save => {
+            match textbuf.save() {
+                Ok(_) => {
+                    let filename = textbuf.filename.clone().unwrap_or_default();
+                    textbuf.set_message(format!("Saved {filename}"));
+                }
+                // no filename yet
+                Err(e) if e.kind() == ErrorKind::NotFound => match save_prompt(textbuf, screen) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        textbuf.set_message(format!("Error: {}", e));
+                    }
+                },
+                Err(e) => {
+                    textbuf.set_message(format!("Error: {}", e));
+                }
             }
 
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Enter, _) => {
-            if textbuf.row_buffer.len() > textbuf.cursor.1 {
-                let element = textbuf.row_buffer[textbuf.cursor.1].split_off(textbuf.cursor.0);
-                textbuf.cursor.0 = 0;
-                textbuf.cursor.1 += 1;
-                textbuf.row_buffer.insert(textbuf.cursor.1, element);
+        Action::Undo => {
+            textbuf.undo();
+            textbuf.dirty = true;
+        }
+
+        Action::Redo => {
+            textbuf.redo();
+            textbuf.dirty = true;
+        }
+
+        Action::Copy => {
+            let text = textbuf
+                .selected_text()
+                .unwrap_or_else(|| format!("{}\n", textbuf.line(textbuf.cursor.1)));
+            clipboard.copy(text, screen);
+            // to send the terminal clipboard
+            textbuf.dirty = true;
+        }
+
+        Action::Cut => {
+            if let Some(text) = textbuf.selected_text() {
+                textbuf.delete_selection();
+                clipboard.copy(text, screen);
             } else {
-                textbuf.row_buffer.push(Vec::new());
-                textbuf.cursor.0 = 0;
-                textbuf.cursor.1 += 1;
+                let row = textbuf.cursor.1;
+                clipboard.copy(format!("{}\n", textbuf.line(row)), screen);
+
+                let (start, end) = line_span(textbuf, row);
+                textbuf.remove(start, end);
+                textbuf.cursor = (0, min(row, textbuf.len_lines() - 1));
             }
 
             textbuf.save_changed = true;
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::Tab, _) => {
-            if textbuf.cursor.1 >= textbuf.row_buffer.len() {
-                textbuf.row_buffer.push(Vec::new());
-            }
+        Action::Search => {
+            search_prompt(textbuf, screen, sh);
+            textbuf.dirty = true;
+        }
 
-            for _ in 0..TABLENGTH {
-                textbuf.row_buffer[textbuf.cursor.1].insert(textbuf.cursor.0, ' ');
-                textbuf.cursor.0 += 1;
+        Action::Replace => {
+            replace_prompt(textbuf, screen, sh);
+            textbuf.dirty = true;
+        }
+
+        Action::Paste => {
+            if let Some(text) = clipboard.paste().map(str::to_string) {
+                textbuf.delete_selection();
+                let start = textbuf.cursor;
+                textbuf.insert_at_cursor(&text);
+                clipboard.last_yank = Some((start, textbuf.cursor));
+
+                textbuf.save_changed = true;
+                textbuf.dirty = true;
             }
+        }
 
-            textbuf.save_changed = true;
+        Action::ToggleLineNumbers => {
+            textbuf.line_numbers = match textbuf.line_numbers {
+                LineNumbers::Off => LineNumbers::Absolute,
+                LineNumbers::Absolute => LineNumbers::Relative,
+                LineNumbers::Relative => LineNumbers::Off,
+            };
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::PageUp, _) => {
-            if textbuf.cursor.1 > textbuf.dimensions.1 as usize {
-                textbuf.cursor.1 -= textbuf.dimensions.1 as usize - 1;
-            } else {
-                textbuf.cursor.1 = 0;
+        // choose the encoding the buffer is saved in
+        Action::SetEncoding => {
+            let prompt = format!("Encoding ({}): ", textbuf.encoding.name());
+            if let Some(name) = input_prompt(&prompt, screen) {
+                match Encoding::from_name(&name) {
+                    Some(encoding) => {
+                        if encoding != textbuf.encoding {
+                            textbuf.encoding = encoding;
+                            textbuf.save_changed = true;
+                        }
+                        textbuf.set_message(format!("Saving as {}", encoding.name()));
+                    }
+                    None if name.is_empty() => {}
+                    None => textbuf.set_message(format!("Unknown encoding '{name}'")),
+                }
             }
 
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::PageDown, _) => {
-            if (textbuf.cursor.1 + textbuf.dimensions.1 as usize) < textbuf.row_buffer.len() {
-                textbuf.cursor.1 += textbuf.dimensions.1 as usize - 1;
-            } else {
-                textbuf.cursor.1 = textbuf.row_buffer.len() - 1;
+        Action::SearchNext | Action::SearchPrevious => {
+            if let Some(search) = &textbuf.search {
+                let forward = action == Action::SearchNext;
+                let cursor = textbuf.file_cursor();
+                let from = if forward {
+                    (cursor.0 + 1, cursor.1)
+                } else {
+                    cursor
+                };
+
+                match search.find(textbuf, from, forward) {
+                    Some((start, _)) => textbuf.set_file_cursor(start),
+                    None => textbuf.set_message(format!("No match for '{}'", search.query)),
+                }
             }
 
             textbuf.dirty = true;
         }
 
-        KeyStroke(KeyCode::End, _) => {
-            textbuf.cursor.0 = textbuf.row_buffer[textbuf.cursor.1].len();
-            textbuf.dirty = true;
+        // replace what was just pasted with the previous kill ring entry
+        Action::PastePrevious => {
+            if let Some((start, end)) = last_yank.filter(|&(_, end)| end == textbuf.cursor) {
+                if let Some(text) = clipboard.rotate().map(str::to_string) {
+                    textbuf.remove(start, end);
+                    textbuf.cursor = start;
+                    textbuf.insert_at_cursor(&text);
+                    clipboard.last_yank = Some((start, textbuf.cursor));
+
+                    textbuf.save_changed = true;
+                    textbuf.dirty = true;
+                }
+            }
         }
 
-        KeyStroke(KeyCode::Home, _) => {
-            textbuf.cursor.0 = 0;
-            textbuf.dirty = true;
+        // editor actions, handled before the buffer gets the action
+        _ => {}
+    }
+}
+
+fn indent_rules(textbuf: &TextBuf, sh: &SynHighlighter) -> IndentRules {
+    indent::rules(&sh.syntax_for(textbuf).name)
+}
+
+/// Whether only spaces and tabs come before the cursor on its line
+fn in_leading_whitespace(textbuf: &TextBuf) -> bool {
+    let (col, row) = textbuf.cursor;
+    indent::leading(textbuf, row, col).chars().count() == col
+}
+
+fn move_cursor(textbuf: &mut TextBuf, motion: Motion) {
+    match motion {
+        Motion::Up => {
+            if textbuf.cursor.1 > 0 {
+                // keep the screen column, which differs from the char column
+                // if either line has tabs
+                let vcol = textbuf.visual_col(textbuf.cursor);
+                textbuf.cursor.1 -= 1;
+                textbuf.cursor.0 = textbuf.col_at_visual(textbuf.cursor.1, vcol);
+            } else {
+                textbuf.cursor.0 = 0;
+            }
         }
 
-        KeyStroke(KeyCode::Delete, _) => {
-            if textbuf.cursor.0 < textbuf.row_buffer[textbuf.cursor.1].len() {
-                textbuf.row_buffer[textbuf.cursor.1].remove(textbuf.cursor.0);
-            } else if textbuf.cursor.1 < textbuf.row_buffer.len() - 1 {
-                let row = textbuf.row_buffer.remove(textbuf.cursor.1 + 1).into_iter();
-                textbuf.row_buffer[textbuf.cursor.1].extend(row);
+        Motion::Down => {
+            if textbuf.cursor.1 < textbuf.len_lines() - 1 {
+                let vcol = textbuf.visual_col(textbuf.cursor);
+                textbuf.cursor.1 += 1;
+                textbuf.cursor.0 = textbuf.col_at_visual(textbuf.cursor.1, vcol);
+            } else {
+                textbuf.cursor.0 = textbuf.line_len(textbuf.cursor.1);
             }
+        }
 
-            textbuf.save_changed = true;
-            textbuf.dirty = true;
+        Motion::Left => {
+            if textbuf.cursor.0 > 0 {
+                textbuf.cursor.0 = textbuf.prev_grapheme(textbuf.cursor);
+            } else if textbuf.cursor.1 > 0 {
+                textbuf.cursor.1 -= 1;
+                textbuf.cursor.0 = textbuf.line_len(textbuf.cursor.1);
+            }
         }
 
-        KeyStroke(KeyCode::Char(c), KeyModifiers::CONTROL) => match c {
-            's' => {
-                match textbuf.save() {
-                    Ok(_) => {}
-                    Err(_) => match save_prompt(textbuf, &mut std::io::stdout()) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            popup(&format!("Error: {}", e), &mut std::io::stdout());
-                        }
-                    },
-                }
+        Motion::Right => {
+            if textbuf.cursor.0 < textbuf.line_len(textbuf.cursor.1) {
+                textbuf.cursor.0 = textbuf.next_grapheme(textbuf.cursor);
+            } else if textbuf.cursor.1 < textbuf.len_lines() - 1 {
+                textbuf.cursor.1 += 1;
+                textbuf.cursor.0 = 0;
+            }
+        }
 
-                textbuf.dirty = true;
+        Motion::PageUp => {
+            if textbuf.cursor.1 > textbuf.text_rows() {
+                textbuf.cursor.1 -= textbuf.text_rows() - 1;
+            } else {
+                textbuf.cursor.1 = 0;
             }
-            'q' => {
-                std::process::exit(0);
+
+            textbuf.clamp_cursor();
+        }
+
+        Motion::PageDown => {
+            if (textbuf.cursor.1 + textbuf.text_rows()) < textbuf.len_lines() {
+                textbuf.cursor.1 += textbuf.text_rows() - 1;
+            } else {
+                textbuf.cursor.1 = textbuf.len_lines() - 1;
             }
-            _ => {}
-        },
-        _ => {}
+
+            textbuf.clamp_cursor();
+        }
+
+        Motion::LineEnd => textbuf.cursor.0 = textbuf.line_len(textbuf.cursor.1),
+        Motion::LineStart => textbuf.cursor.0 = 0,
     }
 }
 
 fn viewport_bounding(textbuf: &mut TextBuf) {
+    textbuf.update_window();
+
     // vertical
     if textbuf.cursor.1 < textbuf.viewport_v_offset {
         textbuf.viewport_v_offset = textbuf.cursor.1;
-    } else if textbuf.cursor.1 >= textbuf.viewport_v_offset + textbuf.dimensions.1 as usize {
-        textbuf.viewport_v_offset = textbuf.cursor.1 - textbuf.dimensions.1 as usize + 1;
+    } else if textbuf.cursor.1 >= textbuf.viewport_v_offset + textbuf.text_rows() {
+        textbuf.viewport_v_offset = textbuf.cursor.1 - textbuf.text_rows() + 1;
+    }
+
+    // wrapped lines take several rows each, and never need scrolling sideways
+    if textbuf.wrap {
+        textbuf.viewport_h_offset = 0;
+        while textbuf.viewport_v_offset < textbuf.cursor.1
+            && textbuf.view_position(textbuf.cursor).0 >= textbuf.text_rows()
+        {
+            textbuf.viewport_v_offset += 1;
+        }
+        return;
+    }
+
+    // horizontal, in screen columns
+    let vcol = textbuf.visual_col(textbuf.cursor);
+    if vcol < textbuf.viewport_h_offset {
+        textbuf.viewport_h_offset = vcol;
+    } else if vcol >= textbuf.viewport_h_offset + textbuf.text_columns() {
+        textbuf.viewport_h_offset = vcol - textbuf.text_columns() + 1;
+    }
+}
+
+/// Draw every pane, the focused one last so it gets the cursor
+pub fn render_editor(editor: &mut Editor, sh: &SynHighlighter) {
+    let focus = editor.focus;
+    let screen = &mut editor.screen;
+    for (idx, (pane, rect)) in editor.layout.panes_mut(editor.area).into_iter().enumerate() {
+        if idx == focus || rect.width == 0 || rect.height == 0 {
+            continue;
+        }
+
+        // lend the buffer to this pane, then put it back as the focused pane
+        // (or whoever showed it last) left it
+        let mut textbuf = editor.buffers[pane.buffer].lock().unwrap();
+        let (view, origin, dimensions) = (textbuf.view(), textbuf.origin, textbuf.dimensions);
+
+        textbuf.set_view(pane.view);
+        textbuf.origin = (rect.x, rect.y);
+        textbuf.dimensions = (rect.width, rect.height);
+        render_pane(&mut textbuf, screen, sh, false);
+        pane.view = textbuf.view();
+
+        textbuf.set_view(view);
+        textbuf.origin = origin;
+        textbuf.dimensions = dimensions;
     }
 
-    // horizontal
-    if textbuf.cursor.0 < textbuf.viewport_h_offset {
-        textbuf.viewport_h_offset = textbuf.cursor.0;
-    } else if textbuf.cursor.0 >= textbuf.viewport_h_offset + textbuf.dimensions.0 as usize {
-        textbuf.viewport_h_offset = textbuf.cursor.0 - textbuf.dimensions.0 as usize + 1;
+    for separator in editor.layout.separators(editor.area) {
+        for y in separator.y..separator.y + separator.height {
+            screen
+                .frame
+                .set(separator.x, y, "│", 1, ContentStyle::new().dark_grey());
+        }
     }
+
+    let buffer = Arc::clone(editor.current());
+    render_textbuf(&mut buffer.lock().unwrap(), &mut editor.screen, sh);
+}
+
+/// Draw the focused pane with the message line and cursor, and show the frame
+pub fn render_textbuf(textbuf: &mut TextBuf, screen: &mut Screen, sh: &SynHighlighter) {
+    render_pane(textbuf, screen, sh, true);
+    render_message_line(textbuf, screen);
+
+    let (view_row, view_col) = textbuf.view_position(textbuf.cursor);
+    screen.cursor = Some((
+        textbuf.origin.0 + (textbuf.gutter_width() + view_col) as u16,
+        textbuf.origin.1 + min(view_row, textbuf.text_rows() - 1) as u16,
+    ));
+    screen.flush();
 }
 
-pub fn render_textbuf(textbuf: &mut TextBuf, stdout: &mut Stdout, sh: &SynHighlighter) {
-    queue!(stdout, cursor::Hide).unwrap();
-    queue!(stdout, crossterm::cursor::MoveTo(0, 0)).unwrap();
+/// Draw the text and status line of the pane at `textbuf.origin`
+fn render_pane(textbuf: &mut TextBuf, screen: &mut Screen, sh: &SynHighlighter, focused: bool) {
+    let (x, y) = textbuf.origin;
+    let width = textbuf.dimensions.0;
+    let frame = &mut screen.frame;
 
     viewport_bounding(textbuf);
 
     let vstart = textbuf.viewport_v_offset;
     let vend = min(
-        textbuf.row_buffer.len(),
-        textbuf.viewport_v_offset + textbuf.dimensions.1 as usize,
+        textbuf.len_lines(),
+        textbuf.viewport_v_offset + textbuf.text_rows(),
     );
 
-    for (idx, row) in textbuf.row_buffer[vstart..vend].iter().enumerate() {
-        queue!(
-            stdout,
-            crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine)
-        )
-        .unwrap();
-
-        let hstart = textbuf.viewport_h_offset;
-        let hend = min(
-            row.len(),
-            textbuf.viewport_h_offset + textbuf.dimensions.0 as usize + 1,
-        );
+    let highlighted = if sh.use_colors {
+        let syntax = sh.syntax_for(textbuf);
+        textbuf
+            .highlight_cache
+            .highlight(&textbuf.rope, vstart..vend, syntax, sh)
+    } else {
+        Vec::new()
+    };
 
-        if hend > hstart {
-            if sh.use_colors {
-                // Parse the line.
-                let line: String = row[hstart..hend].iter().collect();
-                let mut h = syntect::easy::HighlightLines::new(
-                    sh.syntax_set.find_syntax_by_extension("rs").unwrap(),
-                    &sh.theme_set.themes[sh.theme.as_str()],
-                );
-                let ranges: Vec<(syntect::highlighting::Style, &str)> =
-                    h.highlight_line(&line, &sh.syntax_set).unwrap();
-
-                // Print the line with color.
-                for (style, text) in ranges {
-                    let color = syntect_to_crossterm_color(style.foreground);
-                    queue!(stdout, crossterm::style::SetForegroundColor(color)).unwrap();
-                    print!("{}", text);
-                    queue!(stdout, crossterm::style::ResetColor).unwrap();
+    // a wrapped line continues on the rows below it, so lines and screen rows
+    // don't line up
+    let bottom = y + textbuf.text_rows() as u16;
+    let mut y = y;
+    for (idx, row) in (vstart..vend).map(|row| textbuf.line(row)).enumerate() {
+        if y >= bottom {
+            break;
+        }
+        let starts = if textbuf.wrap {
+            textbuf.wrap_starts(vstart + idx)
+        } else {
+            vec![0]
+        };
+
+        // screen columns, which run ahead of char columns after a tab
+        let mut hstart = textbuf.viewport_h_offset;
+        let mut hend = hstart + textbuf.text_columns();
+
+        let mut column = render_gutter(textbuf, frame, (x, y), Some(vstart + idx));
+        let text_x = column;
+
+        let selection = textbuf.selection_in_row(vstart + idx);
+        let is_selected = |col: usize| selection.is_some_and(|(from, to)| from <= col && col < to);
+        let matches = textbuf
+            .search
+            .as_ref()
+            .map(|search| search.find_in_line(row))
+            .unwrap_or_default();
+        let is_match = |col: usize| matches.iter().any(|&(from, to)| from <= col && col < to);
+
+        let colors: Vec<Color> = highlighted
+            .get(idx)
+            .into_iter()
+            .flatten()
+            .flat_map(|(style, text)| {
+                let color = syntect_to_crossterm_color(style.foreground);
+                text.chars().map(move |_| color)
+            })
+            .collect();
+
+        // Draw the visible part of the line, tabs as blanks up to the next
+        // tab stop. Wide characters cut off by the edge of the view are
+        // blanked too.
+        let line = row.to_string();
+        let eol = row.len_chars();
+        let mut vcol = 0;
+        for (col, g) in graphemes(&line).chain([(eol, "")]) {
+            if col > 0 && starts.contains(&col) {
+                frame.clear(column, y, (x + width).saturating_sub(column));
+                y += 1;
+                if y >= bottom {
+                    break;
                 }
+                column = render_gutter(textbuf, frame, (x, y), None);
+                (hstart, hend) = (vcol, vcol + textbuf.text_columns());
+            }
+            if vcol >= hend || g.is_empty() {
+                break;
+            }
+
+            let width = grapheme_width(g, vcol, textbuf.tab_width);
+            let style = char_style(colors.get(col).copied(), is_selected(col), is_match(col));
+            if g != "\t" && hstart <= vcol && vcol + width <= hend {
+                column = frame.set(column, y, printable(g), width as u16, style);
             } else {
-                for c in &row[hstart..hend] {
-                    print!("{}", c);
+                for cell in vcol..vcol + width {
+                    if (hstart..hend).contains(&cell) {
+                        column = frame.set(column, y, " ", 1, style);
+                    }
                 }
             }
+            vcol += width;
+        }
+
+        if y >= bottom {
+            break;
+        }
+
+        // show a selected line break as a highlighted blank
+        if is_selected(eol) && (hstart..hend).contains(&vcol) {
+            column = frame.set(column, y, " ", 1, char_style(None, true, false));
         }
 
-        queue!(stdout, crossterm::cursor::MoveTo(0, idx as u16 + 1)).unwrap();
+        // panes can sit side by side, so only the pane's part of the row is
+        // cleared
+        frame.clear(column, y, (x + width).saturating_sub(column));
+
+        // mark the first column past the longest lines should be
+        if let Some(ruler) = textbuf.ruler.filter(|_| !textbuf.wrap) {
+            if (hstart..hend).contains(&ruler) {
+                let cell = frame.cell(text_x + (ruler - hstart) as u16, y).clone();
+                if !cell.symbol.is_empty() && cell.style.background_color.is_none() {
+                    frame.set(
+                        text_x + (ruler - hstart) as u16,
+                        y,
+                        &cell.symbol,
+                        cell.symbol.width().max(1) as u16,
+                        cell.style.on_dark_grey(),
+                    );
+                }
+            }
+        }
+        y += 1;
     }
 
     // draw tildes
     let empty_line_char = if textbuf.viewport_h_offset == 0 {
-        '~'
+        "~"
     } else {
-        ' '
+        " "
     };
-    for idx in vend..=textbuf.dimensions.1 as usize - 1 {
-        queue!(
-            stdout,
-            crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine)
-        )
-        .unwrap();
-        print!("{empty_line_char}");
-        queue!(stdout, crossterm::cursor::MoveTo(0, idx as u16)).unwrap();
+    for y in y..bottom {
+        frame.set(x, y, empty_line_char, 1, ContentStyle::default());
+        frame.clear(x + 1, y, width.saturating_sub(1));
     }
 
-    queue!(
-        stdout,
-        crossterm::cursor::MoveTo(
-            (textbuf.cursor.0 - textbuf.viewport_h_offset) as u16,
-            (textbuf.cursor.1 - textbuf.viewport_v_offset) as u16
-        )
-    )
-    .unwrap();
-    queue!(stdout, cursor::Show).unwrap();
-    stdout.flush().unwrap();
+    render_status_line(textbuf, frame, sh, focused);
+}
+
+/// Draw the line number of `row` at `position`, or a blank gutter for the
+/// rows a wrapped line continues on, returning the column after it
+fn render_gutter(
+    textbuf: &TextBuf,
+    frame: &mut Grid,
+    (x, y): (u16, u16),
+    row: Option<usize>,
+) -> u16 {
+    let number = match (textbuf.line_numbers, row) {
+        (LineNumbers::Off, _) => return x,
+        (_, None) => String::new(),
+        (LineNumbers::Relative, Some(row)) if row != textbuf.cursor.1 => {
+            row.abs_diff(textbuf.cursor.1).to_string()
+        }
+        (_, Some(row)) => (textbuf.first_line() + row + 1).to_string(),
+    };
+
+    let width = textbuf.gutter_width() - 1;
+    let gutter = format!("{number:>width$} ");
+    frame.print(x, y, &gutter, ContentStyle::new().dark_grey())
+}
+
+fn render_status_line(textbuf: &TextBuf, frame: &mut Grid, sh: &SynHighlighter, focused: bool) {
+    let width = textbuf.dimensions.0 as usize;
+
+    let left = format!(
+        " {}{}{}",
+        textbuf.filename.as_deref().unwrap_or("[No Name]"),
+        if textbuf.save_changed { " [+]" } else { "" },
+        if textbuf.read_only { " [RO]" } else { "" }
+    );
+    let progress = match textbuf.loading {
+        Some((loaded, total)) => format!("Loading {}% | ", loaded * 100 / total.max(1)),
+        None => String::new(),
+    };
+    let right = format!(
+        "{progress}{} | {} | {} | {}:{} / {} ",
+        sh.syntax_for(textbuf).name,
+        textbuf.line_ending.name(),
+        textbuf.encoding.name(),
+        textbuf.first_line() + textbuf.cursor.1 + 1,
+        textbuf.cursor.0 + 1,
+        textbuf.total_lines()
+    );
+    let padding = width.saturating_sub(left.chars().count() + right.chars().count());
+    let status: String = format!("{left}{}{right}", " ".repeat(padding))
+        .chars()
+        .take(width)
+        .collect();
+
+    let style = if focused {
+        ContentStyle::new().negative()
+    } else {
+        ContentStyle::new().dark_grey().negative()
+    };
+    frame.print(
+        textbuf.origin.0,
+        textbuf.origin.1 + textbuf.text_rows() as u16,
+        &status,
+        style,
+    );
+}
+
+fn render_message_line(textbuf: &mut TextBuf, screen: &mut Screen) {
+    textbuf.expire_message();
+
+    let message = textbuf
+        .message
+        .as_ref()
+        .map(|(message, _)| message.as_str())
+        .unwrap_or_default();
+    message_line(screen, message, ContentStyle::default());
+}
+
+/// Replace the message line (the last row) with `text`, returning the column
+/// after it
+fn message_line(screen: &mut Screen, text: &str, style: ContentStyle) -> u16 {
+    let (columns, rows) = screen.size();
+    let y = rows.saturating_sub(1);
+    screen.frame.clear(0, y, columns);
+    screen.frame.print(0, y, text, style)
+}
+
+/// Style of a text cell, from its syntax color and whether it is selected or
+/// part of a search match
+fn char_style(color: Option<Color>, selected: bool, matched: bool) -> ContentStyle {
+    let mut style = ContentStyle::new();
+    if let Some(color) = color {
+        style = style.with(color);
+    }
+    if matched {
+        style = style.black().on_yellow();
+    }
+    if selected {
+        style = style.negative();
+    }
+    style
+}
+
+/// Control characters (like a stray CR) would move the terminal's cursor, and
+/// lone combining marks would join the character drawn before them
+fn printable(g: &str) -> &str {
+    if g.chars().any(char::is_control) || g.width() == 0 {
+        "?"
+    } else {
+        g
+    }
 }
 
 fn syntect_to_crossterm_color(color: SyntectColor) -> Color {
@@ -410,25 +998,46 @@ fn syntect_to_crossterm_color(color: SyntectColor) -> Color {
     }
 }
 
-pub fn popup(message: &str, stdout: &mut Stdout) {
-    // Move to top left corner and print message
-    queue!(stdout, cursor::MoveTo(0, 0)).unwrap();
-    queue!(
-        stdout,
-        crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine)
-    )
-    .unwrap();
-    print!("{}", message.negative());
+pub fn popup(message: &str, screen: &mut Screen) {
+    // show the message on the message line, with the cursor after it
+    let (columns, rows) = screen.size();
+    let end = message_line(screen, message, ContentStyle::new().negative());
+    screen.cursor = Some((end.min(columns.saturating_sub(1)), rows.saturating_sub(1)));
+
+    screen.flush();
+}
 
-    stdout.flush().unwrap();
+/// Read a line of text on the prompt line. Returns `None` if cancelled with Esc.
+pub fn input_prompt(label: &str, screen: &mut Screen) -> Option<String> {
+    let mut input = String::new();
+    loop {
+        popup(format!("{label}{input}").as_str(), screen);
+        let key = get_key();
+        match key.0 {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Enter => return Some(input),
+            KeyCode::Esc => return None,
+            _ => continue,
+        };
+    }
 }
 
-pub fn save_prompt(textbuf: &mut TextBuf, stdout: &mut Stdout) -> Result<(), std::io::Error> {
+pub fn save_prompt(textbuf: &mut TextBuf, screen: &mut Screen) -> Result<(), std::io::Error> {
     if !textbuf.save_changed {
         return Ok(());
     }
 
-    popup("Save file? (y/n)", stdout);
+    popup(
+        format!(
+            "Save changes to {}? (y/n)",
+            textbuf.filename.as_deref().unwrap_or("[No Name]")
+        )
+        .as_str(),
+        screen,
+    );
 
     loop {
         let key = get_key();
@@ -439,25 +1048,9 @@ pub fn save_prompt(textbuf: &mut TextBuf, stdout: &mut Stdout) -> Result<(), std
                 }
                 Err(e) => {
                     if e.kind() == ErrorKind::NotFound {
-                        let mut filename = String::new();
-                        loop {
-                            popup(format!("Enter filename: {filename}").as_str(), stdout);
-                            let key = get_key();
-                            match key.0 {
-                                KeyCode::Char(c) => filename.push(c),
-                                KeyCode::Backspace => {
-                                    filename.pop();
-                                }
-                                KeyCode::Enter => break,
-                                KeyCode::Esc => {
-                                    return Err(std::io::Error::new(
-                                        ErrorKind::Other,
-                                        "User cancelled!",
-                                    ))
-                                }
-                                _ => continue,
-                            };
-                        }
+                        let Some(filename) = input_prompt("Enter filename: ", screen) else {
+                            return Err(std::io::Error::other("User cancelled!"));
+                        };
                         textbuf.filename = Some(filename.clone());
                         match textbuf.save() {
                             Ok(_) => {
@@ -466,26 +1059,522 @@ pub fn save_prompt(textbuf: &mut TextBuf, stdout: &mut Stdout) -> Result<(), std
                             Err(_) => {
                                 popup(
                                     (format!("Error with filename: '{filename}' ({e})!")).as_str(),
-                                    stdout,
+                                    screen,
                                 );
                                 get_key();
-                                return Err(std::io::Error::new(
-                                    ErrorKind::Other,
-                                    "Error saving file!",
-                                ));
+                                return Err(std::io::Error::other("Error saving file!"));
                             }
                         }
                     } else {
-                        popup("Error saving file!", stdout);
+                        popup("Error saving file!", screen);
                         get_key();
-                        return Err(std::io::Error::new(ErrorKind::Other, "Error saving file!"));
+                        return Err(std::io::Error::other("Error saving file!"));
                     }
                 }
             },
             KeyCode::Char('n') => {
                 return Ok(());
             }
+            KeyCode::Esc => {
+                return Err(std::io::Error::other("User cancelled!"));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Ask to save every modified buffer before quitting. Cancelling any of them
+/// cancels the quit.
+pub fn quit_prompt(editor: &mut Editor, sh: &SynHighlighter) -> Result<(), std::io::Error> {
+    for idx in 0..editor.buffers.len() {
+        if !editor.buffers[idx].lock().unwrap().save_changed {
+            continue;
+        }
+
+        // show the buffer being asked about
+        editor.switch_to(idx);
+        render_editor(editor, sh);
+
+        let buffer = Arc::clone(editor.current());
+        let mut textbuf = buffer.lock().unwrap();
+        if let Err(e) = save_prompt(&mut textbuf, &mut editor.screen) {
+            textbuf.dirty = true;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Pick a buffer on the prompt line with Left/Right (or its number) and Enter
+pub fn buffer_picker(editor: &mut Editor) -> Option<usize> {
+    let names = editor.buffer_names();
+    let mut selected = editor.current_index();
+
+    loop {
+        let list: Vec<String> = names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                if idx == selected {
+                    format!("[{}:{name}]", idx + 1)
+                } else {
+                    format!(" {}:{name} ", idx + 1)
+                }
+            })
+            .collect();
+        popup(
+            format!("Buffers: {}", list.join("")).as_str(),
+            &mut editor.screen,
+        );
+
+        match get_key().0 {
+            KeyCode::Left | KeyCode::Up | KeyCode::BackTab => {
+                selected = (selected + names.len() - 1) % names.len();
+            }
+            KeyCode::Right | KeyCode::Down | KeyCode::Tab => {
+                selected = (selected + 1) % names.len();
+            }
+            KeyCode::Char(c) => {
+                if let Some(idx) = c.to_digit(10).map(|n| n as usize) {
+                    if (1..=names.len()).contains(&idx) {
+                        return Some(idx - 1);
+                    }
+                }
+            }
+            KeyCode::Enter => return Some(selected),
+            KeyCode::Esc => return None,
             _ => {}
         }
     }
 }
+
+/// Incremental search. The cursor follows the first match after where the
+/// search started; Up/Down step through matches, Alt-C toggles case
+/// sensitivity and Alt-W wrap-around. Esc goes back to where the search started.
+pub fn search_prompt(textbuf: &mut TextBuf, screen: &mut Screen, sh: &SynHighlighter) {
+    let origin = textbuf.file_cursor();
+    let mut search = Search {
+        query: String::new(),
+        ..textbuf.search.take().unwrap_or_default()
+    };
+    let mut found = true;
+
+    textbuf.selection_anchor = None;
+
+    loop {
+        textbuf.search = Some(search.clone());
+        render_textbuf(textbuf, screen, sh);
+        popup(
+            format!(
+                "Search{}{}: {}{}",
+                if search.case_sensitive { " [Aa]" } else { "" },
+                if search.wrap_around { " [wrap]" } else { "" },
+                search.query,
+                if found { "" } else { " (no match)" }
+            )
+            .as_str(),
+            screen,
+        );
+
+        let key = get_key();
+        let (from, forward) = match key {
+            KeyStroke(KeyCode::Char('c'), KeyModifiers::ALT) => {
+                search.case_sensitive = !search.case_sensitive;
+                (origin, true)
+            }
+            KeyStroke(KeyCode::Char('w'), KeyModifiers::ALT) => {
+                search.wrap_around = !search.wrap_around;
+                continue;
+            }
+            KeyStroke(KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                search.query.push(c);
+                (origin, true)
+            }
+            KeyStroke(KeyCode::Backspace, _) => {
+                search.query.pop();
+                (origin, true)
+            }
+            KeyStroke(KeyCode::Down, _) => {
+                let cursor = textbuf.file_cursor();
+                ((cursor.0 + 1, cursor.1), true)
+            }
+            KeyStroke(KeyCode::Up, _) => (textbuf.file_cursor(), false),
+            KeyStroke(KeyCode::Enter, _) => break,
+            KeyStroke(KeyCode::Esc, _) => {
+                textbuf.set_file_cursor(origin);
+                textbuf.search = None;
+                break;
+            }
+            _ => continue,
+        };
+
+        match search.find(textbuf, from, forward) {
+            Some((start, _)) => {
+                textbuf.set_file_cursor(start);
+                found = true;
+            }
+            None => {
+                if from == origin {
+                    textbuf.set_file_cursor(origin);
+                }
+                found = search.query.is_empty();
+            }
+        }
+    }
+}
+
+/// Regex find-and-replace from the cursor onwards, wrapping around. Every match
+/// is confirmed with y/n, `a` replaces the rest without asking and q/Esc stops.
+/// The replacement may refer to capture groups as `$1` or `${name}`.
+pub fn replace_prompt(textbuf: &mut TextBuf, screen: &mut Screen, sh: &SynHighlighter) {
+    let Some(pattern) = input_prompt("Replace: ", screen) else {
+        return;
+    };
+    let regex = match Regex::new(&pattern) {
+        Ok(regex) => regex,
+        Err(e) => {
+            popup(format!("Invalid pattern: {e}").as_str(), screen);
+            get_key();
+            return;
+        }
+    };
+    let Some(replacement) = input_prompt(format!("Replace '{pattern}' with: ").as_str(), screen)
+    else {
+        return;
+    };
+
+    // (start, end, replacement) in chars for every match in the buffer as it is now
+    let text = textbuf.rope.to_string();
+    let mut matches: Vec<(usize, usize, String)> = regex
+        .captures_iter(&text)
+        .map(|caps| {
+            let whole = caps.get(0).unwrap();
+            let mut with = String::new();
+            caps.expand(&replacement, &mut with);
+            (
+                textbuf.rope.byte_to_char(whole.start()),
+                textbuf.rope.byte_to_char(whole.end()),
+                with,
+            )
+        })
+        .collect();
+
+    let cursor = textbuf.char_idx(textbuf.cursor);
+    let first = matches
+        .iter()
+        .position(|&(start, _, _)| start >= cursor)
+        .unwrap_or(0);
+    matches.rotate_left(first);
+
+    // (original start, change in length) of every replacement made so far
+    let mut shifts: Vec<(usize, isize)> = Vec::new();
+    let mut replaced = 0;
+    let mut replace_all = false;
+
+    'matches: for (start, end, with) in matches {
+        let offset: isize = shifts
+            .iter()
+            .filter(|&&(at, _)| at < start)
+            .map(|&(_, shift)| shift)
+            .sum();
+        let from = textbuf.position(start.saturating_add_signed(offset));
+        let to = textbuf.position(end.saturating_add_signed(offset));
+
+        if !replace_all {
+            textbuf.cursor = from;
+            textbuf.selection_anchor = Some(to);
+            render_textbuf(textbuf, screen, sh);
+            popup("Replace? (y/n/a/q)", screen);
+
+            loop {
+                match get_key().0 {
+                    KeyCode::Char('y') => break,
+                    KeyCode::Char('n') => continue 'matches,
+                    KeyCode::Char('a') => {
+                        replace_all = true;
+                        break;
+                    }
+                    KeyCode::Char('q') | KeyCode::Esc => break 'matches,
+                    _ => {}
+                }
+            }
+        }
+
+        textbuf.remove(from, to);
+        textbuf.cursor = from;
+        textbuf.insert_at_cursor(&with);
+        shifts.push((
+            start,
+            with.chars().count() as isize - (end - start) as isize,
+        ));
+        textbuf.save_changed = true;
+        replaced += 1;
+    }
+
+    textbuf.selection_anchor = None;
+    textbuf.set_message(format!("Replaced {replaced} occurrence(s)"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::TestBackend, config::Config, screen::Screen};
+
+    fn editor(width: u16, height: u16) -> (Editor, TestBackend, SynHighlighter) {
+        let backend = TestBackend::new(width, height);
+        let mut editor = Editor::new(
+            Screen::new(Box::new(backend.clone())),
+            Clipboard::new(false),
+            Config::default(),
+            false,
+            false,
+        );
+        let sh = SynHighlighter::from("base16-ocean.dark".to_string(), false, None);
+        editor.open(None, &sh);
+        (editor, backend, sh)
+    }
+
+    fn keys(editor: &mut Editor, sh: &SynHighlighter, keys: &[(KeyCode, KeyModifiers)]) {
+        for &(code, modifiers) in keys {
+            process_event(InputEvent::KeyStroke(code, modifiers), editor, sh);
+        }
+    }
+
+    fn type_text(editor: &mut Editor, sh: &SynHighlighter, text: &str) {
+        for c in text.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                c => KeyCode::Char(c),
+            };
+            keys(editor, sh, &[(code, KeyModifiers::NONE)]);
+        }
+    }
+
+    #[test]
+    fn typed_text_is_drawn() {
+        let (mut editor, backend, sh) = editor(60, 6);
+        type_text(&mut editor, &sh, "hello\nworld");
+        render_editor(&mut editor, &sh);
+
+        assert_eq!(backend.row(0), "hello");
+        assert_eq!(backend.row(1), "world");
+        assert_eq!(backend.row(2), "~");
+        assert!(backend.row(4).starts_with(" [No Name] [+]"));
+        assert!(backend.row(4).ends_with("2:6 / 2"));
+        assert_eq!(backend.cursor(), Some((5, 1)));
+    }
+
+    #[test]
+    fn typing_redraws_only_changed_cells() {
+        let (mut editor, backend, sh) = editor(60, 6);
+        type_text(&mut editor, &sh, "hello\nworld");
+        render_editor(&mut editor, &sh);
+        backend.take_drawn();
+
+        // the new character and the cursor position on the status line
+        type_text(&mut editor, &sh, "!");
+        render_editor(&mut editor, &sh);
+        assert_eq!(backend.row(1), "world!");
+        assert!(backend.take_drawn() <= 4);
+
+        render_editor(&mut editor, &sh);
+        assert_eq!(backend.take_drawn(), 0);
+    }
+
+    #[test]
+    fn undo_drops_the_selection() {
+        let (mut editor, _backend, sh) = editor(40, 6);
+        type_text(&mut editor, &sh, "hello world");
+        keys(&mut editor, &sh, &[(KeyCode::Left, KeyModifiers::SHIFT); 5]);
+        keys(
+            &mut editor,
+            &sh,
+            &[(KeyCode::Char('z'), KeyModifiers::CONTROL)],
+        );
+        type_text(&mut editor, &sh, "x");
+
+        let textbuf = editor.current().lock().unwrap();
+        assert_eq!(textbuf.rope.to_string(), "x");
+        assert_eq!(textbuf.selection_anchor, None);
+    }
+
+    #[test]
+    fn moving_the_cursor_ends_an_undo_step() {
+        let (mut editor, _backend, sh) = editor(40, 6);
+        type_text(&mut editor, &sh, "ab");
+        keys(
+            &mut editor,
+            &sh,
+            &[
+                (KeyCode::Left, KeyModifiers::NONE),
+                (KeyCode::Right, KeyModifiers::NONE),
+            ],
+        );
+        type_text(&mut editor, &sh, "c");
+        keys(
+            &mut editor,
+            &sh,
+            &[(KeyCode::Char('z'), KeyModifiers::CONTROL)],
+        );
+
+        assert_eq!(editor.current().lock().unwrap().rope.to_string(), "ab");
+    }
+
+    #[test]
+    fn line_cut_and_copy_without_selection() {
+        let (mut editor, _backend, sh) = editor(40, 6);
+        type_text(&mut editor, &sh, "one\ntwo\nthree");
+        keys(
+            &mut editor,
+            &sh,
+            &[
+                (KeyCode::Up, KeyModifiers::NONE),
+                (KeyCode::Char('c'), KeyModifiers::CONTROL),
+            ],
+        );
+        assert_eq!(editor.clipboard.paste(), Some("two\n"));
+
+        keys(
+            &mut editor,
+            &sh,
+            &[
+                (KeyCode::Up, KeyModifiers::NONE),
+                (KeyCode::Char('x'), KeyModifiers::CONTROL),
+            ],
+        );
+        assert_eq!(editor.clipboard.paste(), Some("one\n"));
+        let textbuf = editor.current().lock().unwrap();
+        assert_eq!(textbuf.rope.to_string(), "two\nthree");
+        assert_eq!(textbuf.cursor, (0, 0));
+    }
+
+    #[test]
+    fn selection_is_drawn_in_reverse() {
+        let (mut editor, backend, sh) = editor(40, 6);
+        type_text(&mut editor, &sh, "abc");
+        keys(
+            &mut editor,
+            &sh,
+            &[
+                (KeyCode::Home, KeyModifiers::NONE),
+                (KeyCode::Right, KeyModifiers::SHIFT),
+            ],
+        );
+        render_editor(&mut editor, &sh);
+
+        let reverse = |x| {
+            backend
+                .cell(x, 0)
+                .style
+                .attributes
+                .has(crossterm::style::Attribute::Reverse)
+        };
+        assert!(reverse(0));
+        assert!(!reverse(1));
+    }
+
+    #[test]
+    fn vertical_split_shows_both_panes() {
+        let (mut editor, backend, sh) = editor(21, 6);
+        type_text(&mut editor, &sh, "text");
+        keys(
+            &mut editor,
+            &sh,
+            &[(KeyCode::Char('\\'), KeyModifiers::ALT)],
+        );
+        render_editor(&mut editor, &sh);
+
+        assert_eq!(backend.row(0), "text      │text");
+        assert_eq!(backend.cursor(), Some((4, 0)));
+    }
+
+    #[test]
+    fn resize_moves_status_line() {
+        let (mut editor, backend, sh) = editor(40, 6);
+        render_editor(&mut editor, &sh);
+
+        backend.resize(30, 4);
+        process_event(InputEvent::Resize(30, 4), &mut editor, &sh);
+        render_editor(&mut editor, &sh);
+
+        assert!(backend.row(2).starts_with(" [No Name]"));
+        assert_eq!(backend.row(3), "");
+    }
+
+    #[test]
+    fn wide_characters_take_two_columns() {
+        let (mut editor, backend, sh) = editor(40, 6);
+        type_text(&mut editor, &sh, "a中b");
+        keys(&mut editor, &sh, &[(KeyCode::Left, KeyModifiers::NONE)]);
+        render_editor(&mut editor, &sh);
+
+        assert_eq!(backend.row(0), "a中b");
+        assert_eq!(backend.cursor(), Some((3, 0)));
+
+        keys(&mut editor, &sh, &[(KeyCode::Left, KeyModifiers::NONE)]);
+        render_editor(&mut editor, &sh);
+        assert_eq!(backend.cursor(), Some((1, 0)));
+    }
+
+    #[test]
+    fn graphemes_move_and_delete_as_one() {
+        let (mut editor, backend, sh) = editor(40, 6);
+        // e with a combining acute accent, and a family emoji joined with ZWJs
+        type_text(
+            &mut editor,
+            &sh,
+            "xe\u{301}\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}y",
+        );
+        keys(
+            &mut editor,
+            &sh,
+            &[
+                (KeyCode::Left, KeyModifiers::NONE),
+                (KeyCode::Left, KeyModifiers::NONE),
+            ],
+        );
+        render_editor(&mut editor, &sh);
+        assert_eq!(editor.current().lock().unwrap().cursor, (3, 0));
+        assert_eq!(backend.cursor(), Some((2, 0)));
+
+        keys(
+            &mut editor,
+            &sh,
+            &[(KeyCode::Backspace, KeyModifiers::NONE)],
+        );
+        keys(&mut editor, &sh, &[(KeyCode::Delete, KeyModifiers::NONE)]);
+        render_editor(&mut editor, &sh);
+        assert_eq!(backend.row(0), "xy");
+        assert_eq!(backend.cursor(), Some((1, 0)));
+    }
+
+    #[test]
+    fn long_lines_wrap() {
+        let (mut editor, backend, sh) = editor(12, 6);
+        editor.current().lock().unwrap().wrap = true;
+        type_text(&mut editor, &sh, "abcdefghijklmnop\nxyz");
+        keys(&mut editor, &sh, &[(KeyCode::Up, KeyModifiers::NONE)]);
+        render_editor(&mut editor, &sh);
+
+        assert_eq!(backend.row(0), "abcdefghijkl");
+        assert_eq!(backend.row(1), "mnop");
+        assert_eq!(backend.row(2), "xyz");
+        assert_eq!(backend.cursor(), Some((3, 0)));
+    }
+
+    #[test]
+    fn unchanged_frame_draws_nothing() {
+        let (mut editor, backend, sh) = editor(60, 6);
+        render_editor(&mut editor, &sh);
+        backend.take_drawn();
+
+        render_editor(&mut editor, &sh);
+        assert_eq!(backend.take_drawn(), 0);
+
+        type_text(&mut editor, &sh, "a");
+        render_editor(&mut editor, &sh);
+        // the character, the status line's [+] and the column number
+        assert!(backend.take_drawn() < 10);
+    }
+}
@@ -0,0 +1,160 @@
+use std::io::Stdout;
+
+use crossterm::{
+    event::{
+        KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute,
+};
+
+/// Ask the terminal to report keys unambiguously (the kitty keyboard
+/// protocol), so Ctrl-I isn't Tab and Esc isn't the start of an Alt key. Has to
+/// run before the input thread starts reading. Returns whether the terminal
+/// supports it.
+pub fn enable_enhancement(stdout: &mut Stdout) -> bool {
+    if !crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false) {
+        return false;
+    }
+
+    execute!(
+        stdout,
+        PushKeyboardEnhancementFlags(
+            KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                | KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS
+        )
+    )
+    .unwrap();
+    true
+}
+
+pub fn disable_enhancement(stdout: &mut Stdout) {
+    execute!(stdout, PopKeyboardEnhancementFlags).unwrap();
+}
+
+/// The key press `event` stands for, the same whichever way the terminal
+/// reports it. Releases (and bare modifier keys) give `None`, and a held key's
+/// repeats count as presses.
+///
+/// Typed characters carry no modifiers, since Shift (or AltGr, which some
+/// platforms report as Ctrl+Alt) is already part of the character. Shortcuts
+/// are a lowercase letter with Shift as a modifier.
+pub fn normalize(event: KeyEvent) -> Option<(KeyCode, KeyModifiers)> {
+    if event.kind == KeyEventKind::Release || matches!(event.code, KeyCode::Modifier(_)) {
+        return None;
+    }
+
+    let mut modifiers = event.modifiers;
+    let altgr = KeyModifiers::CONTROL | KeyModifiers::ALT;
+    let code = match event.code {
+        KeyCode::Char(c) if modifiers.contains(altgr) && !c.is_ascii_alphanumeric() => {
+            modifiers.remove(altgr | KeyModifiers::SHIFT);
+            KeyCode::Char(c)
+        }
+        KeyCode::Char(c) if modifiers.intersects(altgr) => {
+            if c.is_uppercase() {
+                modifiers.insert(KeyModifiers::SHIFT);
+            } else if !c.is_lowercase() {
+                // a symbol, which Shift already picked
+                modifiers.remove(KeyModifiers::SHIFT);
+            }
+            KeyCode::Char(c.to_lowercase().next().unwrap_or(c))
+        }
+        KeyCode::Char(c) => {
+            let shifted = modifiers.contains(KeyModifiers::SHIFT);
+            modifiers.remove(KeyModifiers::SHIFT);
+            if shifted && c.is_lowercase() {
+                KeyCode::Char(c.to_uppercase().next().unwrap_or(c))
+            } else {
+                KeyCode::Char(c)
+            }
+        }
+        // Shift-Tab, which says Shift in its name already
+        KeyCode::BackTab => {
+            modifiers.remove(KeyModifiers::SHIFT);
+            KeyCode::BackTab
+        }
+        code => code,
+    };
+
+    Some((code, modifiers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers, kind: KeyEventKind) -> KeyEvent {
+        KeyEvent::new_with_kind(code, modifiers, kind)
+    }
+
+    #[test]
+    fn normalize_keys() {
+        let press = |code, modifiers| normalize(key(code, modifiers, KeyEventKind::Press));
+
+        // characters typed with Shift or AltGr
+        assert_eq!(
+            press(KeyCode::Char('A'), KeyModifiers::SHIFT),
+            Some((KeyCode::Char('A'), KeyModifiers::NONE))
+        );
+        assert_eq!(
+            press(KeyCode::Char('a'), KeyModifiers::SHIFT),
+            Some((KeyCode::Char('A'), KeyModifiers::NONE))
+        );
+        assert_eq!(
+            press(
+                KeyCode::Char('@'),
+                KeyModifiers::CONTROL | KeyModifiers::ALT
+            ),
+            Some((KeyCode::Char('@'), KeyModifiers::NONE))
+        );
+
+        // shortcuts
+        assert_eq!(
+            press(KeyCode::Char('Z'), KeyModifiers::CONTROL),
+            Some((
+                KeyCode::Char('z'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            ))
+        );
+        assert_eq!(
+            press(KeyCode::Char('s'), KeyModifiers::CONTROL),
+            Some((KeyCode::Char('s'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            press(KeyCode::Char('<'), KeyModifiers::ALT | KeyModifiers::SHIFT),
+            Some((KeyCode::Char('<'), KeyModifiers::ALT))
+        );
+        assert_eq!(
+            press(KeyCode::Char('<'), KeyModifiers::ALT),
+            Some((KeyCode::Char('<'), KeyModifiers::ALT))
+        );
+        assert_eq!(
+            press(KeyCode::Left, KeyModifiers::SHIFT),
+            Some((KeyCode::Left, KeyModifiers::SHIFT))
+        );
+        assert_eq!(
+            press(KeyCode::BackTab, KeyModifiers::SHIFT),
+            Some((KeyCode::BackTab, KeyModifiers::NONE))
+        );
+
+        // repeats act like presses, releases are dropped
+        assert_eq!(
+            normalize(key(
+                KeyCode::Char('x'),
+                KeyModifiers::NONE,
+                KeyEventKind::Repeat
+            )),
+            Some((KeyCode::Char('x'), KeyModifiers::NONE))
+        );
+        assert_eq!(
+            normalize(key(
+                KeyCode::Char('x'),
+                KeyModifiers::NONE,
+                KeyEventKind::Release
+            )),
+            None
+        );
+    }
+}
@@ -0,0 +1,525 @@
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::{keyboard, window::Direction};
+
+/// Where a movement action takes the cursor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Motion {
+    Up,
+    Down,
+    Left,
+    Right,
+    LineStart,
+    LineEnd,
+    PageUp,
+    PageDown,
+}
+
+/// Something the editor can do, whatever key it is bound to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    InsertChar(char), // typed characters, which have no binding
+    Move(Motion),
+    Select(Motion), // move, extending the selection
+    InsertNewline,
+    InsertTab,
+    DeleteBackward,
+    DeleteForward,
+    Undo,
+    Redo,
+    Copy,
+    Cut,
+    Paste,
+    PastePrevious, // replace what was just pasted with the previous copy
+    Save,
+    Quit,      // asking to save modified buffers
+    ForceQuit, // without asking
+    Search,
+    SearchNext,
+    SearchPrevious,
+    Replace,
+    ToggleLineNumbers,
+    SetEncoding,
+    OpenFile,
+    NextBuffer,
+    PreviousBuffer,
+    PickBuffer,
+    SplitHorizontal,
+    SplitVertical,
+    ClosePane,
+    Focus(Direction),
+    ResizePane(Direction),
+}
+
+// names used in keymap files
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("move_up", Action::Move(Motion::Up)),
+    ("move_down", Action::Move(Motion::Down)),
+    ("move_left", Action::Move(Motion::Left)),
+    ("move_right", Action::Move(Motion::Right)),
+    ("move_line_start", Action::Move(Motion::LineStart)),
+    ("move_line_end", Action::Move(Motion::LineEnd)),
+    ("page_up", Action::Move(Motion::PageUp)),
+    ("page_down", Action::Move(Motion::PageDown)),
+    ("select_up", Action::Select(Motion::Up)),
+    ("select_down", Action::Select(Motion::Down)),
+    ("select_left", Action::Select(Motion::Left)),
+    ("select_right", Action::Select(Motion::Right)),
+    ("select_line_start", Action::Select(Motion::LineStart)),
+    ("select_line_end", Action::Select(Motion::LineEnd)),
+    ("select_page_up", Action::Select(Motion::PageUp)),
+    ("select_page_down", Action::Select(Motion::PageDown)),
+    ("insert_newline", Action::InsertNewline),
+    ("insert_tab", Action::InsertTab),
+    ("delete_backward", Action::DeleteBackward),
+    ("delete_forward", Action::DeleteForward),
+    ("undo", Action::Undo),
+    ("redo", Action::Redo),
+    ("copy", Action::Copy),
+    ("cut", Action::Cut),
+    ("paste", Action::Paste),
+    ("paste_previous", Action::PastePrevious),
+    ("save", Action::Save),
+    ("quit", Action::Quit),
+    ("force_quit", Action::ForceQuit),
+    ("search", Action::Search),
+    ("search_next", Action::SearchNext),
+    ("search_previous", Action::SearchPrevious),
+    ("replace", Action::Replace),
+    ("toggle_line_numbers", Action::ToggleLineNumbers),
+    ("set_encoding", Action::SetEncoding),
+    ("open_file", Action::OpenFile),
+    ("next_buffer", Action::NextBuffer),
+    ("previous_buffer", Action::PreviousBuffer),
+    ("pick_buffer", Action::PickBuffer),
+    ("split_horizontal", Action::SplitHorizontal),
+    ("split_vertical", Action::SplitVertical),
+    ("close_pane", Action::ClosePane),
+    ("focus_up", Action::Focus(Direction::Up)),
+    ("focus_down", Action::Focus(Direction::Down)),
+    ("focus_left", Action::Focus(Direction::Left)),
+    ("focus_right", Action::Focus(Direction::Right)),
+    ("resize_pane_up", Action::ResizePane(Direction::Up)),
+    ("resize_pane_down", Action::ResizePane(Direction::Down)),
+    ("resize_pane_left", Action::ResizePane(Direction::Left)),
+    ("resize_pane_right", Action::ResizePane(Direction::Right)),
+];
+
+const DEFAULT_KEYMAP: &[(&str, Action)] = &[
+    ("Up", Action::Move(Motion::Up)),
+    ("Down", Action::Move(Motion::Down)),
+    ("Left", Action::Move(Motion::Left)),
+    ("Right", Action::Move(Motion::Right)),
+    ("Home", Action::Move(Motion::LineStart)),
+    ("End", Action::Move(Motion::LineEnd)),
+    ("PageUp", Action::Move(Motion::PageUp)),
+    ("PageDown", Action::Move(Motion::PageDown)),
+    ("Shift-Up", Action::Select(Motion::Up)),
+    ("Shift-Down", Action::Select(Motion::Down)),
+    ("Shift-Left", Action::Select(Motion::Left)),
+    ("Shift-Right", Action::Select(Motion::Right)),
+    ("Shift-Home", Action::Select(Motion::LineStart)),
+    ("Shift-End", Action::Select(Motion::LineEnd)),
+    ("Shift-PageUp", Action::Select(Motion::PageUp)),
+    ("Shift-PageDown", Action::Select(Motion::PageDown)),
+    ("Enter", Action::InsertNewline),
+    ("Tab", Action::InsertTab),
+    ("Backspace", Action::DeleteBackward),
+    ("Delete", Action::DeleteForward),
+    ("Ctrl-z", Action::Undo),
+    ("Ctrl-y", Action::Redo),
+    ("Ctrl-c", Action::Copy),
+    ("Ctrl-x", Action::Cut),
+    ("Ctrl-v", Action::Paste),
+    ("Alt-v", Action::PastePrevious),
+    ("Ctrl-s", Action::Save),
+    ("Esc", Action::Quit),
+    ("Ctrl-q", Action::ForceQuit),
+    ("Ctrl-f", Action::Search),
+    ("F3", Action::SearchNext),
+    ("Alt-n", Action::SearchNext),
+    ("Shift-F3", Action::SearchPrevious),
+    ("Alt-p", Action::SearchPrevious),
+    ("Ctrl-r", Action::Replace),
+    ("Alt-l", Action::ToggleLineNumbers),
+    ("Alt-e", Action::SetEncoding),
+    ("Ctrl-o", Action::OpenFile),
+    ("Ctrl-PageDown", Action::NextBuffer),
+    ("Alt-.", Action::NextBuffer),
+    ("Ctrl-PageUp", Action::PreviousBuffer),
+    ("Alt-,", Action::PreviousBuffer),
+    ("Ctrl-b", Action::PickBuffer),
+    ("Alt--", Action::SplitHorizontal),
+    ("Alt-\\", Action::SplitVertical),
+    ("Alt-w", Action::ClosePane),
+    ("Alt-Up", Action::Focus(Direction::Up)),
+    ("Alt-Down", Action::Focus(Direction::Down)),
+    ("Alt-Left", Action::Focus(Direction::Left)),
+    ("Alt-Right", Action::Focus(Direction::Right)),
+    ("Alt-Shift-Up", Action::ResizePane(Direction::Up)),
+    ("Alt-Shift-Down", Action::ResizePane(Direction::Down)),
+    ("Alt-Shift-Left", Action::ResizePane(Direction::Left)),
+    ("Alt-Shift-Right", Action::ResizePane(Direction::Right)),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTION_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, action)| action)
+    }
+
+    pub fn name(&self) -> &'static str {
+        ACTION_NAMES
+            .iter()
+            .find(|(_, action)| action == self)
+            .map_or("insert_char", |&(name, _)| name)
+    }
+
+    /// Whether the action changes the buffer's text or how it is saved
+    pub fn is_edit(&self) -> bool {
+        matches!(
+            self,
+            Action::InsertChar(_)
+                | Action::InsertNewline
+                | Action::InsertTab
+                | Action::DeleteBackward
+                | Action::DeleteForward
+                | Action::Undo
+                | Action::Redo
+                | Action::Cut
+                | Action::Paste
+                | Action::PastePrevious
+                | Action::Replace
+                | Action::SetEncoding
+        )
+    }
+}
+
+/// A key with its modifiers, as `keyboard::normalize` reports it. Written like
+/// `Ctrl-s`, `Alt-Shift-Left` or `F3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chord(pub KeyCode, pub KeyModifiers);
+
+impl FromStr for Chord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut key = s;
+        // "Alt--" is Alt with the minus key
+        while let Some((prefix, rest)) = key.split_once('-').filter(|(_, rest)| !rest.is_empty()) {
+            modifiers |= match prefix.to_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                "super" => KeyModifiers::SUPER,
+                _ => return Err(format!("unknown modifier '{prefix}' in '{s}'")),
+            };
+            key = rest;
+        }
+
+        let lowercase = key.to_lowercase();
+        let code = match lowercase.as_str() {
+            "esc" => KeyCode::Esc,
+            "enter" => KeyCode::Enter,
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "space" => KeyCode::Char(' '),
+            name if name.len() > 1 && name.starts_with('f') && name[1..].parse::<u8>().is_ok() => {
+                KeyCode::F(name[1..].parse().unwrap())
+            }
+            _ => {
+                let mut chars = key.chars();
+                match (chars.next(), chars.next()) {
+                    // shortcuts are lowercase, with Shift spelled out
+                    (Some(c), None)
+                        if modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+                    {
+                        KeyCode::Char(c.to_lowercase().next().unwrap_or(c))
+                    }
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => return Err(format!("unknown key '{key}' in '{s}'")),
+                }
+            }
+        };
+
+        let (code, normalized) = keyboard::normalize(KeyEvent::new(code, modifiers))
+            .ok_or_else(|| format!("unknown key '{s}'"))?;
+        // like Shift-a or Ctrl-Alt-/ (AltGr), which type a character
+        if let (KeyCode::Char(c), KeyModifiers::NONE) = (code, normalized) {
+            if !modifiers.is_empty() {
+                return Err(format!("'{s}' types '{c}', it can't be bound"));
+            }
+        }
+        Ok(Chord(code, normalized))
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl-"),
+            (KeyModifiers::ALT, "Alt-"),
+            (KeyModifiers::SHIFT, "Shift-"),
+            (KeyModifiers::SUPER, "Super-"),
+        ] {
+            if self.1.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+
+        match self.0 {
+            KeyCode::Char(' ') => f.write_str("Space"),
+            KeyCode::Char(c) => write!(f, "{c}"),
+            KeyCode::F(n) => write!(f, "F{n}"),
+            code => write!(f, "{code:?}"),
+        }
+    }
+}
+
+/// Chords pressed one after the other, written separated by spaces like
+/// `Ctrl-k Ctrl-s`
+pub fn parse_sequence(s: &str) -> Result<Vec<Chord>, String> {
+    let keys: Vec<Chord> = s
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    if keys.is_empty() {
+        return Err("empty key sequence".to_string());
+    }
+    Ok(keys)
+}
+
+pub fn format_sequence(keys: &[Chord]) -> String {
+    keys.iter()
+        .map(Chord::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub enum Lookup {
+    Action(Action),
+    Prefix, // the start of a longer sequence, wait for the next key
+    Unbound,
+}
+
+/// Which key sequences do what
+pub struct Keymap {
+    bindings: HashMap<Vec<Chord>, Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = DEFAULT_KEYMAP
+            .iter()
+            .map(|&(keys, action)| (parse_sequence(keys).unwrap(), action))
+            .collect();
+        Keymap { bindings }
+    }
+}
+
+impl Keymap {
+    /// The default keymap with the bindings in the keymap file at `path`
+    /// applied on top, if there is one. Also returns anything wrong with the
+    /// file, to report.
+    pub fn load(path: &Path) -> (Keymap, Vec<String>) {
+        let mut keymap = Keymap::default();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return (keymap, Vec::new()),
+            Err(e) => return (keymap, vec![e.to_string()]),
+        };
+
+        let problems = match text.parse::<toml::Table>() {
+            Ok(table) => keymap.bind_all(&table),
+            Err(e) => vec![e.message().to_string()],
+        };
+        (keymap, problems)
+    }
+
+    /// Add bindings from a table of `"keys" = "action"`, where the action
+    /// `none` unbinds the keys. They replace default bindings for the same
+    /// keys, or ones that start with them or they start with (which would
+    /// leave one of the two unreachable). Returns the problems found, with
+    /// replaced defaults of the second kind among them.
+    pub fn bind_all(&mut self, table: &toml::Table) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut bindings: Vec<(Vec<Chord>, Option<Action>, &str)> = Vec::new();
+        for (keys, value) in table {
+            let sequence = match parse_sequence(keys) {
+                Ok(sequence) => sequence,
+                Err(e) => {
+                    problems.push(e);
+                    continue;
+                }
+            };
+            if let Chord(KeyCode::Char(c), KeyModifiers::NONE) = sequence[0] {
+                problems.push(format!("'{keys}' would stop '{c}' from being typed"));
+                continue;
+            }
+            let action = match value.as_str() {
+                Some("none") => None,
+                Some(name) => match Action::from_name(name) {
+                    Some(action) => Some(action),
+                    None => {
+                        problems.push(format!("unknown action '{name}' for '{keys}'"));
+                        continue;
+                    }
+                },
+                None => {
+                    problems.push(format!("the action for '{keys}' isn't a string"));
+                    continue;
+                }
+            };
+            // different spellings of the same keys
+            if let Some((_, _, other)) = bindings.iter().find(|(other, ..)| *other == sequence) {
+                problems.push(format!("'{keys}' and '{other}' are the same keys"));
+                continue;
+            }
+            bindings.push((sequence, action, keys));
+        }
+
+        for (sequence, ..) in &bindings {
+            self.bindings.remove(sequence);
+        }
+
+        let mut bound: Vec<&[Chord]> = Vec::new();
+        for (sequence, action, keys) in &bindings {
+            let Some(action) = action else {
+                continue;
+            };
+
+            let overlapping: Vec<Vec<Chord>> = self
+                .bindings
+                .keys()
+                .filter(|other| other.starts_with(sequence) || sequence.starts_with(other))
+                .cloned()
+                .collect();
+            if let Some(other) = overlapping
+                .iter()
+                .find(|other| bound.contains(&other.as_slice()))
+            {
+                problems.push(format!(
+                    "'{keys}' conflicts with '{}', one starts with the other",
+                    format_sequence(other)
+                ));
+                continue;
+            }
+            for other in overlapping {
+                let replaced = self.bindings.remove(&other).unwrap();
+                problems.push(format!(
+                    "'{keys}' replaces '{}' ({})",
+                    format_sequence(&other),
+                    replaced.name()
+                ));
+            }
+
+            self.bindings.insert(sequence.clone(), *action);
+            bound.push(sequence);
+        }
+
+        problems
+    }
+
+    /// What the keys pressed so far do
+    pub fn lookup(&self, keys: &[Chord]) -> Lookup {
+        if let Some(&action) = self.bindings.get(keys) {
+            Lookup::Action(action)
+        } else if self
+            .bindings
+            .keys()
+            .any(|sequence| sequence.starts_with(keys))
+        {
+            Lookup::Prefix
+        } else {
+            Lookup::Unbound
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(s: &str) -> Chord {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_chords() {
+        assert_eq!(
+            chord("Ctrl-S"),
+            Chord(KeyCode::Char('s'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(chord("Alt--"), Chord(KeyCode::Char('-'), KeyModifiers::ALT));
+        assert_eq!(chord("Alt-Shift-<"), chord("Alt-<"));
+        assert_eq!(
+            chord("Shift-Tab"),
+            Chord(KeyCode::BackTab, KeyModifiers::NONE)
+        );
+        assert_eq!(
+            chord("alt-shift-left"),
+            Chord(KeyCode::Left, KeyModifiers::ALT | KeyModifiers::SHIFT)
+        );
+        assert_eq!(chord("F12"), Chord(KeyCode::F(12), KeyModifiers::NONE));
+        assert!("Hyper-x".parse::<Chord>().is_err());
+        assert!("Ctrl-Foo".parse::<Chord>().is_err());
+        assert!("Ctrl-Alt-/".parse::<Chord>().is_err());
+        assert!("Shift-a".parse::<Chord>().is_err());
+
+        for s in ["Ctrl-Shift-z", "Alt-PageDown", "Space", "Shift-F3"] {
+            assert_eq!(chord(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn user_bindings() {
+        let mut keymap = Keymap::default();
+        let table: toml::Table = toml::from_str(
+            r#"
+            "Ctrl-k Ctrl-s" = "save"
+            "Ctrl-x Ctrl-s" = "save"
+            "Ctrl-f" = "none"
+            "Ctrl-g" = "teleport"
+            "Ctrl-Alt-/" = "save"
+            "x" = "save"
+            "Ctrl-k x" = "quit"
+            "#,
+        )
+        .unwrap();
+        let problems = keymap.bind_all(&table);
+
+        let keys = parse_sequence("Ctrl-k Ctrl-s").unwrap();
+        assert!(matches!(keymap.lookup(&keys[..1]), Lookup::Prefix));
+        assert!(matches!(keymap.lookup(&keys), Lookup::Action(Action::Save)));
+        assert!(matches!(keymap.lookup(&[chord("Ctrl-f")]), Lookup::Unbound));
+        // Ctrl-x alone would cut before the sequence could be finished
+        assert!(matches!(keymap.lookup(&[chord("Ctrl-x")]), Lookup::Prefix));
+
+        assert_eq!(
+            problems,
+            [
+                "'Ctrl-Alt-/' types '/', it can't be bound",
+                "unknown action 'teleport' for 'Ctrl-g'",
+                "'x' would stop 'x' from being typed",
+                "'Ctrl-x Ctrl-s' replaces 'Ctrl-x' (cut)",
+            ]
+        );
+    }
+}
@@ -1,6 +1,24 @@
 mod args;
+mod backend;
+mod clipboard;
+mod config;
+mod editor;
+mod editorconfig;
+mod encoding;
+mod events;
+mod highlight;
+mod history;
+mod indent;
 mod io;
+mod keyboard;
+mod keymap;
+mod mapped;
+mod screen;
+mod search;
 mod textbuf;
+mod window;
+
+use std::path::PathBuf;
 
 use clap::Parser;
 use crossterm::{
@@ -10,31 +28,49 @@ use crossterm::{
         disable_raw_mode, enable_raw_mode, Clear, EnterAlternateScreen, LeaveAlternateScreen,
     },
 };
-use io::{nonblocking_get_event, process_event, InputEvent};
-use std::{
-    io::Write,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use events::Event;
+use io::{end_prompts, process_event};
 
-use syntect::{highlighting::ThemeSet, parsing::SyntaxSet};
+use syntect::{
+    highlighting::ThemeSet,
+    parsing::{SyntaxReference, SyntaxSet},
+};
 
 use crate::{
-    io::{popup, render_textbuf, save_prompt},
-    textbuf::TextBuf,
+    backend::CrosstermBackend,
+    clipboard::Clipboard,
+    config::Config,
+    editor::Editor,
+    io::render_editor,
+    keymap::Keymap,
+    screen::Screen,
+    textbuf::{LineNumbers, TextBuf},
 };
 
 pub const TABLENGTH: usize = 4;
+pub const DEFAULT_THEME: &str = "base16-eighties.dark";
+
+/// Where configuration files go: `$XDG_CONFIG_HOME/edrs`, `~/.config/edrs`
+/// if that isn't set, or `%APPDATA%\edrs` on Windows
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("edrs"))
+}
 
 pub struct SynHighlighter {
     pub syntax_set: SyntaxSet,
     pub theme_set: ThemeSet,
     pub theme: String,
     pub use_colors: bool,
+    pub lang: Option<String>,
 }
 
 impl SynHighlighter {
-    fn from(theme: String, use_colors: bool) -> Self {
+    fn from(theme: String, use_colors: bool, lang: Option<String>) -> Self {
         let syntax_set = SyntaxSet::load_defaults_newlines();
         let theme_set = ThemeSet::load_defaults();
 
@@ -43,8 +79,40 @@ impl SynHighlighter {
             theme_set,
             theme,
             use_colors,
+            lang,
         }
     }
+
+    /// Pick the syntax for a buffer: the `--lang` override, then the file
+    /// extension (or name, e.g. `Makefile`), then the first line (shebangs,
+    /// modelines), and plain text if nothing matches.
+    pub fn syntax_for(&self, textbuf: &TextBuf) -> &SyntaxReference {
+        let by_first_line = || {
+            self.syntax_set
+                .find_syntax_by_first_line(&textbuf.line(0).to_string())
+        };
+
+        self.syntax_for_file(textbuf.filename.as_deref())
+            .or_else(by_first_line)
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text())
+    }
+
+    /// The `--lang` override, then the syntax for `filename`, for when the
+    /// text isn't loaded yet
+    pub fn syntax_for_file(&self, filename: Option<&str>) -> Option<&SyntaxReference> {
+        let by_lang = || {
+            self.lang
+                .as_ref()
+                .and_then(|lang| self.syntax_set.find_syntax_by_token(lang))
+        };
+        let by_filename = || {
+            let path = std::path::Path::new(filename?);
+            let name = path.extension().or(path.file_name())?.to_str()?;
+            self.syntax_set.find_syntax_by_extension(name)
+        };
+
+        by_lang().or_else(by_filename)
+    }
 }
 
 fn main() {
@@ -55,68 +123,110 @@ fn main() {
     enable_raw_mode().unwrap();
     let mut stdout = std::io::stdout();
     execute!(stdout, crossterm::event::EnableMouseCapture).unwrap();
+    execute!(stdout, crossterm::event::EnableBracketedPaste).unwrap();
     execute!(stdout, Clear(crossterm::terminal::ClearType::All)).unwrap();
     execute!(stdout, SetCursorStyle::BlinkingBlock).unwrap();
     execute!(stdout, EnterAlternateScreen).unwrap();
+    let keyboard_enhanced = keyboard::enable_enhancement(&mut stdout);
+
+    // settings from config files, with the command line taking precedence.
+    // Anything wrong with them is shown once a buffer is open.
+    let (mut config, mut problems) = Config::load();
+    if args.relative_numbers {
+        config.overrides.line_numbers = Some(LineNumbers::Relative);
+    } else if args.line_numbers {
+        config.overrides.line_numbers = Some(LineNumbers::Absolute);
+    }
 
     // SynHighlighter setup
-    let syn_highlighter = SynHighlighter::from(args.theme, args.syntax);
-
-    // initialize textbuf
-    let textbuf = Arc::new(Mutex::new(TextBuf::new()));
-    let textbuf_clone = Arc::clone(&textbuf);
-
-    let thread_load = if let Some(file) = args.file {
-        std::thread::spawn(move || {
-            if let Err(e) = TextBuf::async_load(&file, &textbuf_clone) {
-                popup(
-                    format!("Error loading file: {}", e).as_str(),
-                    &mut std::io::stdout(),
-                );
-            }
-        })
+    let mut syn_highlighter = SynHighlighter::from(
+        args.theme
+            .or(config.theme.clone())
+            .unwrap_or(DEFAULT_THEME.to_string()),
+        args.syntax || config.syntax.unwrap_or(false),
+        args.lang,
+    );
+    if !syn_highlighter
+        .theme_set
+        .themes
+        .contains_key(&syn_highlighter.theme)
+    {
+        problems.push(format!("unknown theme '{}'", syn_highlighter.theme));
+        syn_highlighter.theme = DEFAULT_THEME.to_string();
+    }
+    problems.extend(config.unknown_filetypes(&syn_highlighter.syntax_set));
+
+    let mut editor = Editor::new(
+        Screen::new(Box::new(CrosstermBackend::new())),
+        Clipboard::new(args.osc52),
+        config,
+        args.backup,
+        args.mmap,
+    );
+    editor.spawn_file_watcher();
+
+    // key bindings
+    if let Some(dir) = config_dir() {
+        let keymap_problems;
+        (editor.keymap, keymap_problems) = Keymap::load(&dir.join("keymap.toml"));
+        problems.extend(
+            keymap_problems
+                .into_iter()
+                .map(|problem| format!("keymap.toml: {problem}")),
+        );
+    }
+
+    // initialize textbufs
+    if args.files.is_empty() {
+        editor.open(None, &syn_highlighter);
     } else {
-        std::thread::spawn(move || {})
-    };
+        for file in args.files {
+            editor.open(Some(file), &syn_highlighter);
+        }
+        editor.switch_to(0);
+    }
+    if !problems.is_empty() {
+        editor
+            .current()
+            .lock()
+            .unwrap()
+            .set_message(problems.join("; "));
+    }
 
-    execute!(stdout, crossterm::cursor::MoveTo(0, 0)).unwrap();
-    stdout.flush().unwrap();
+    events::spawn_input_thread();
 
     // main loop
-    loop {
-        // draw textbuf
-        let mut textbuf_guard = textbuf.lock().unwrap();
-        if textbuf_guard.dirty {
-            render_textbuf(&mut textbuf_guard, &mut stdout, &syn_highlighter);
-            textbuf_guard.dirty = true;
+    'main: loop {
+        // draw panes, only if something changed
+        if editor.needs_redraw() {
+            render_editor(&mut editor, &syn_highlighter);
+            editor.drawn();
         }
-        stdout.flush().unwrap();
-        drop(textbuf_guard);
-
-        // wait for keypress
-        if let Some(key) = nonblocking_get_event() {
-            if key
-                == InputEvent::KeyStroke(
-                    crossterm::event::KeyCode::Esc,
-                    crossterm::event::KeyModifiers::NONE,
-                )
-            {
-                let mut textbuf_guard = textbuf.lock().unwrap();
-                match save_prompt(&mut textbuf_guard, &mut stdout) {
-                    Ok(_) => break,
-                    Err(_) => continue,
+
+        // wait for something to happen, then handle everything that queued up
+        // meanwhile before drawing again
+        let mut next = Some(events::next(editor.next_deadline()));
+        while let Some(event) = next {
+            match event {
+                Event::Input(input) => {
+                    process_event(input, &mut editor, &syn_highlighter);
+                    end_prompts();
+                }
+                Event::Loaded => {} // the loader marked its buffer dirty
+                Event::FileChanged => editor.reload_changed_files(),
+                Event::Timer => {
+                    editor.expire_messages();
+                    editor.autosave();
                 }
             }
-
-            // process keypress
-            let mut textbuf_guard = textbuf.lock().unwrap();
-            process_event(key, &mut textbuf_guard);
+            if editor.quit {
+                break 'main;
+            }
+            next = events::try_next();
         }
-
-        std::thread::sleep(Duration::from_millis(10));
     }
 
-    thread_load.join().unwrap();
+    editor.join_loaders();
 
     // terminal cleanup
     disable_raw_mode().unwrap();
@@ -127,6 +237,10 @@ fn main() {
         crossterm::terminal::Clear(crossterm::terminal::ClearType::All)
     )
     .unwrap();
+    execute!(stdout, crossterm::event::DisableBracketedPaste).unwrap();
+    if keyboard_enhanced {
+        keyboard::disable_enhancement(&mut stdout);
+    }
     execute!(stdout, LeaveAlternateScreen).unwrap();
     execute!(stdout, crossterm::cursor::MoveTo(0, 0)).unwrap();
     execute!(stdout, SetCursorStyle::DefaultUserShape).unwrap();
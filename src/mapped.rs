@@ -0,0 +1,75 @@
+use std::{fs::File, ops::Range, sync::Arc};
+
+use memmap2::Mmap;
+
+/// A file opened read-only through a memory map, for files too large to load
+/// into a rope. Only a window of lines around the cursor is copied into the
+/// buffer; `TextBuf::update_window` moves it.
+#[derive(Debug, Clone)]
+pub struct MappedFile {
+    pub mmap: Arc<Mmap>,
+    line_starts: Vec<usize>, // byte offset of every line start found so far
+    complete: bool,          // whether the whole file has been indexed
+    pub first_line: usize,   // file line shown in the buffer's first row
+    pub window_len: usize,   // file lines copied into the buffer
+}
+
+impl PartialEq for MappedFile {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.mmap, &other.mmap)
+            && self.first_line == other.first_line
+            && self.window_len == other.window_len
+    }
+}
+
+impl MappedFile {
+    pub fn open(file: &File) -> Result<Self, std::io::Error> {
+        // Safety: the file must not be truncated while it is mapped. Reading
+        // a page past the new end raises SIGBUS, which kills the editor.
+        // Other changes to the file only show up as changed text.
+        let mmap = unsafe { Mmap::map(file)? };
+
+        Ok(MappedFile {
+            mmap: Arc::new(mmap),
+            line_starts: vec![0],
+            complete: false,
+            first_line: 0,
+            window_len: 0,
+        })
+    }
+
+    /// Record line starts found by the indexer, up to byte `indexed`
+    pub fn add_line_starts(&mut self, starts: &[usize], indexed: usize) {
+        self.line_starts.extend_from_slice(starts);
+        self.complete = indexed >= self.mmap.len();
+    }
+
+    /// Number of lines indexed so far
+    pub fn len_lines(&self) -> usize {
+        let starts = self.line_starts.len();
+        if !self.complete {
+            // the last line isn't known to have ended yet
+            starts - 1
+        } else if starts > 1 && self.line_starts[starts - 1] == self.mmap.len() {
+            // the file ends with a line break
+            starts - 1
+        } else {
+            starts
+        }
+    }
+
+    /// Text of `lines` separated by LF, with invalid UTF-8 replaced
+    pub fn text(&self, lines: Range<usize>) -> String {
+        if lines.is_empty() {
+            return String::new();
+        }
+
+        let start = self.line_starts[lines.start];
+        let end = match self.line_starts.get(lines.end) {
+            Some(&next) => next - 1, // without the line break
+            None => self.mmap.len(),
+        };
+
+        String::from_utf8_lossy(&self.mmap[start..end]).replace("\r\n", "\n")
+    }
+}
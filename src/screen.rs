@@ -0,0 +1,192 @@
+use crossterm::style::ContentStyle;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::backend::Backend;
+
+/// One screen column. A wide character is drawn in its first cell, and the
+/// cells after it that it covers are left empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub symbol: String,
+    pub style: ContentStyle,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            symbol: " ".to_string(),
+            style: ContentStyle::default(),
+        }
+    }
+}
+
+/// A screen's worth of cells, row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    pub width: u16,
+    pub height: u16,
+    cells: Vec<Cell>,
+}
+
+impl Grid {
+    pub fn new(width: u16, height: u16) -> Self {
+        Grid {
+            width,
+            height,
+            cells: vec![Cell::default(); width as usize * height as usize],
+        }
+    }
+
+    pub fn cell(&self, x: u16, y: u16) -> &Cell {
+        &self.cells[y as usize * self.width as usize + x as usize]
+    }
+
+    /// Set the cell at (x, y) to a grapheme taking `width` columns, emptying
+    /// the cells it covers. Returns the column after it. Graphemes that don't
+    /// fit on the grid are left out.
+    pub fn set(&mut self, x: u16, y: u16, symbol: &str, width: u16, style: ContentStyle) -> u16 {
+        if x.saturating_add(width) > self.width || y >= self.height {
+            return x.saturating_add(width);
+        }
+
+        let row = y as usize * self.width as usize;
+        self.cells[row + x as usize] = Cell {
+            symbol: symbol.to_string(),
+            style,
+        };
+        for x in x + 1..x + width {
+            self.cells[row + x as usize] = Cell {
+                symbol: String::new(),
+                style,
+            };
+        }
+        x + width
+    }
+
+    /// Write `text` from (x, y), cut off at the right edge. Returns the
+    /// column after it.
+    pub fn print(&mut self, x: u16, y: u16, text: &str, style: ContentStyle) -> u16 {
+        let mut x = x;
+        for g in text.graphemes(true) {
+            let width = g.width().max(1) as u16;
+            if x + width > self.width {
+                break;
+            }
+            x = self.set(x, y, g, width, style);
+        }
+        x
+    }
+
+    /// Fill `width` cells from (x, y) with blanks
+    pub fn clear(&mut self, x: u16, y: u16, width: u16) {
+        for x in x..x.saturating_add(width).min(self.width) {
+            self.set(x, y, " ", 1, ContentStyle::default());
+        }
+    }
+}
+
+/// Off-screen frame the editor draws into. `flush` sends only the cells that
+/// changed since the last flush to the backend.
+pub struct Screen {
+    pub frame: Grid,                // the frame being drawn
+    shown: Grid,                    // what the backend shows
+    pub cursor: Option<(u16, u16)>, // where the cursor is shown, hidden if `None`
+    invalid: bool,                  // the backend's contents are unknown
+    backend: Box<dyn Backend>,
+}
+
+impl Screen {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        let (width, height) = backend.size();
+        Screen {
+            frame: Grid::new(width, height),
+            shown: Grid::new(width, height),
+            cursor: None,
+            invalid: true,
+            backend,
+        }
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        (self.frame.width, self.frame.height)
+    }
+
+    /// Start over with blank frames, redrawing everything on the next flush
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.frame = Grid::new(width, height);
+        self.shown = Grid::new(width, height);
+        self.invalid = true;
+    }
+
+    /// Put `text` on the terminal's clipboard, sent with the next flush
+    pub fn set_clipboard(&mut self, text: &str) {
+        self.backend.set_clipboard(text);
+    }
+
+    pub fn flush(&mut self) {
+        if self.invalid {
+            self.backend.clear();
+            self.shown = Grid::new(self.frame.width, self.frame.height);
+            self.invalid = false;
+        }
+
+        let mut changed = Vec::new();
+        for y in 0..self.frame.height {
+            for x in 0..self.frame.width {
+                let cell = self.frame.cell(x, y);
+                if cell != self.shown.cell(x, y) {
+                    changed.push((x, y, cell.clone()));
+                }
+            }
+        }
+
+        self.backend.draw(&changed);
+        self.backend.set_cursor(self.cursor);
+        self.backend.flush();
+        self.shown.clone_from(&self.frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::TestBackend;
+
+    #[test]
+    fn flush_draws_only_changed_cells() {
+        let backend = TestBackend::new(10, 2);
+        let mut screen = Screen::new(Box::new(backend.clone()));
+
+        screen.frame.print(0, 0, "hello", ContentStyle::default());
+        screen.flush();
+        assert_eq!(backend.row(0), "hello");
+        assert_eq!(backend.take_drawn(), 5);
+
+        screen.frame.print(0, 0, "help", ContentStyle::default());
+        screen.flush();
+        assert_eq!(backend.row(0), "helpo");
+        assert_eq!(backend.take_drawn(), 1);
+
+        screen.flush();
+        assert_eq!(backend.take_drawn(), 0);
+    }
+
+    #[test]
+    fn resize_redraws_everything() {
+        let backend = TestBackend::new(10, 2);
+        let mut screen = Screen::new(Box::new(backend.clone()));
+        screen.frame.print(0, 1, "text", ContentStyle::default());
+        screen.flush();
+
+        backend.resize(6, 3);
+        screen.resize(6, 3);
+        screen.frame.print(0, 2, "text", ContentStyle::default());
+        screen.cursor = Some((4, 2));
+        screen.flush();
+
+        assert_eq!(backend.row(1), "");
+        assert_eq!(backend.row(2), "text");
+        assert_eq!(backend.cursor(), Some((4, 2)));
+    }
+}
@@ -0,0 +1,104 @@
+use ropey::RopeSlice;
+
+use crate::textbuf::TextBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    pub query: String,
+    pub case_sensitive: bool,
+    pub wrap_around: bool,
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Search {
+            query: String::new(),
+            case_sensitive: false,
+            wrap_around: true,
+        }
+    }
+}
+
+impl Search {
+    fn chars_eq(&self, a: char, b: char) -> bool {
+        a == b || (!self.case_sensitive && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    /// Column ranges (start, end) of every match in `line`
+    pub fn find_in_line(&self, line: RopeSlice) -> Vec<(usize, usize)> {
+        let needle: Vec<char> = self.query.chars().collect();
+        if needle.is_empty() {
+            return Vec::new();
+        }
+
+        let haystack: Vec<char> = line.chars().collect();
+        let mut matches = Vec::new();
+        let mut col = 0;
+        while col + needle.len() <= haystack.len() {
+            if needle
+                .iter()
+                .zip(&haystack[col..])
+                .all(|(&a, &b)| self.chars_eq(a, b))
+            {
+                matches.push((col, col + needle.len()));
+                col += needle.len();
+            } else {
+                col += 1;
+            }
+        }
+        matches
+    }
+
+    /// Find the first match starting at or after `from` (forward), or the last
+    /// one starting before it (backward), wrapping around the end of the buffer
+    /// if enabled. Positions are in the file, which for memory mapped files is
+    /// more than the buffer holds.
+    pub fn find(
+        &self,
+        textbuf: &TextBuf,
+        from: (usize, usize),
+        forward: bool,
+    ) -> Option<((usize, usize), (usize, usize))> {
+        let rows = textbuf.total_lines();
+
+        // the starting row is visited twice: once for the part after `from`,
+        // and again after wrapping for the part before it
+        for step in 0..=rows {
+            let wrapped = if forward {
+                from.1 + step >= rows
+            } else {
+                step > from.1
+            };
+            if wrapped && !self.wrap_around {
+                break;
+            }
+
+            let row = if forward {
+                (from.1 + step) % rows
+            } else {
+                (from.1 + rows - step % rows) % rows
+            };
+
+            let matches = self.find_in_line(RopeSlice::from(&*textbuf.file_line(row)));
+            let found = if forward {
+                matches.into_iter().find(|&(start, _)| match step {
+                    0 => start >= from.0,
+                    s if s == rows => start < from.0,
+                    _ => true,
+                })
+            } else {
+                matches.into_iter().rev().find(|&(start, _)| match step {
+                    0 => start < from.0,
+                    s if s == rows => start >= from.0,
+                    _ => true,
+                })
+            };
+
+            if let Some((start, end)) = found {
+                return Some(((start, row), (end, row)));
+            }
+        }
+
+        None
+    }
+}
@@ -1,55 +1,755 @@
 use std::{
-    fs::OpenOptions,
-    io::{Read, Write},
+    borrow::Cow,
+    cmp::Ordering,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crossterm::terminal;
+use ropey::{Rope, RopeSlice};
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-use crate::TABLENGTH;
+use crate::{
+    editorconfig::Properties,
+    encoding::{Decoder, Encoding},
+    events::{self, Event},
+    highlight::HighlightCache,
+    history::History,
+    mapped::MappedFile,
+    search::Search,
+    TABLENGTH,
+};
+
+// files are read (or indexed, when memory mapped) this many bytes at a time
+const LOAD_CHUNK_SIZE: usize = 1 << 20;
+
+// lines of a memory mapped file copied into the buffer, and how close the
+// cursor gets to either end of them before the window moves
+const WINDOW_LINES: usize = 4096;
+const WINDOW_MARGIN: usize = WINDOW_LINES / 4;
+
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineNumbers {
+    #[default]
+    Off,
+    Absolute,
+    Relative, // distance from the cursor row, which shows its absolute number
+}
+
+/// The part of a `TextBuf` that belongs to a window pane rather than the text,
+/// so several panes can show the same buffer at different places. Rows are
+/// lines of the file, which differ from the buffer's for memory mapped files.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct View {
+    pub cursor: (usize, usize),
+    pub viewport_v_offset: usize,
+    pub viewport_h_offset: usize,
+    pub selection_anchor: Option<(usize, usize)>,
+}
+
+/// Line break style of a file. The rope always uses LF and the file's style
+/// is restored on save.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    /// Style of the first line break in `text`
+    pub fn detect(text: &str) -> Self {
+        match text.find(['\n', '\r']) {
+            Some(idx) if text[idx..].starts_with("\r\n") => LineEnding::CrLf,
+            Some(idx) if text[idx..].starts_with('\r') => LineEnding::Cr,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
+    }
+}
+
+/// Converts line breaks to LF as a file streams in, taking the style from
+/// the first break. Only breaks of that style are converted, so a stray CR in
+/// an LF or CRLF file stays part of its line. A CRLF or CR file that also has
+/// a lone LF can't keep it apart from the converted breaks, so it is read
+/// again as an LF file with every CR kept.
+#[derive(Debug, Default)]
+struct LineBreaks {
+    style: Option<LineEnding>,
+    pending_cr: bool, // CR at the end of the last chunk, which may start a CRLF
+}
+
+impl LineBreaks {
+    /// Breaks of a file read as LF, whatever its first break was
+    fn keep_all() -> Self {
+        LineBreaks {
+            style: Some(LineEnding::Lf),
+            pending_cr: false,
+        }
+    }
+
+    /// The chunk with its line breaks converted, or `None` if it has breaks
+    /// of another style that would be lost
+    fn normalize(&mut self, chunk: &str, last: bool) -> Option<String> {
+        let mut text = String::with_capacity(chunk.len() + 1);
+        if std::mem::take(&mut self.pending_cr) {
+            text.push('\r');
+        }
+        text.push_str(chunk);
+
+        if !last && text.ends_with('\r') && matches!(self.style, None | Some(LineEnding::CrLf)) {
+            text.pop();
+            self.pending_cr = true;
+        }
+
+        if self.style.is_none() && text.contains(['\n', '\r']) {
+            self.style = Some(LineEnding::detect(&text));
+        }
+
+        let lone_lf = text
+            .match_indices('\n')
+            .any(|(idx, _)| !text[..idx].ends_with('\r'));
+        match self.style {
+            Some(LineEnding::CrLf) if lone_lf => None,
+            Some(LineEnding::Cr) if text.contains('\n') => None,
+            Some(LineEnding::CrLf) => Some(text.replace("\r\n", "\n")),
+            Some(LineEnding::Cr) => Some(text.replace('\r', "\n")),
+            _ => Some(text),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TextBuf {
-    pub row_buffer: Vec<Vec<char>>,
+    pub rope: Rope,
     pub cursor: (usize, usize),
+    pub origin: (u16, u16), // top left corner of the pane showing the buffer
     pub dimensions: (u16, u16),
     pub viewport_v_offset: usize, // offset to (start row, end row) of viewport
     pub viewport_h_offset: usize,
     pub filename: Option<String>,
     pub save_changed: bool,
     pub dirty: bool,
+    pub history: History,
+    pub selection_anchor: Option<(usize, usize)>,
+    pub search: Option<Search>,
+    pub highlight_cache: HighlightCache,
+    pub message: Option<(String, Instant)>, // shown on the message line until it times out
+    pub line_numbers: LineNumbers,
+    pub tab_width: usize,
+    pub indent_width: usize, // spaces Tab inserts when inserting spaces
+    pub insert_spaces: bool, // Tab inserts spaces rather than a tab
+    pub wrap: bool,          // long lines continue on the next screen row
+    pub backup: bool,        // keep the file as loaded as `file~` on the first save
+    pub line_ending: LineEnding,
+    pub final_newline: bool, // whether the file ends with a line break
+    pub encoding: Encoding,
+    pub read_only: bool,
+    pub loading: Option<(u64, u64)>, // bytes loaded and total, while loading
+    pub closed: bool,                // tells loader threads to stop
+    pub mapped: Option<MappedFile>,
+    pub disk_mtime: Option<SystemTime>, // modification time of the file when last loaded or saved
+    pub changed_on_disk: bool,          // set by the file watcher until the editor handles it
+    pub last_edit: Option<Instant>,     // when the text last changed, until it is saved
+    pub ruler: Option<usize>,           // column marked as the longest lines should reach
+    pub editorconfig: Properties,       // what `.editorconfig` files say about the file
+    restore_view: Option<View>,         // view to go back to once a reload finishes
+    backed_up: Option<PathBuf>,         // file copied to `file~` since the buffer was loaded
 }
 
 impl TextBuf {
     pub fn new() -> Self {
-        let dimensions = terminal::size().unwrap(); // (columns, rows)
-
-        let vec: Vec<Vec<char>> = Vec::new();
-
         TextBuf {
-            row_buffer: vec,
+            rope: Rope::new(),
             cursor: (0, 0),
-            dimensions,
+            origin: (0, 0),
+            dimensions: (0, 0), // set once the buffer is shown in a pane
             viewport_v_offset: 0,
             viewport_h_offset: 0,
             filename: None,
             save_changed: false,
             dirty: true, // force draw at start
+            history: History::default(),
+            selection_anchor: None,
+            search: None,
+            highlight_cache: HighlightCache::default(),
+            message: None,
+            line_numbers: LineNumbers::Off,
+            tab_width: TABLENGTH,
+            indent_width: TABLENGTH,
+            insert_spaces: true,
+            wrap: false,
+            backup: false,
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+            encoding: Encoding::Utf8,
+            read_only: false,
+            loading: None,
+            closed: false,
+            mapped: None,
+            disk_mtime: None,
+            changed_on_disk: false,
+            last_edit: None,
+            ruler: None,
+            editorconfig: Properties::default(),
+            restore_view: None,
+            backed_up: None,
+        }
+    }
+
+    /// Rows available for text, above the status line
+    pub fn text_rows(&self) -> usize {
+        self.dimensions.1.saturating_sub(1).max(1) as usize
+    }
+
+    /// Width of the line number gutter, including its padding
+    pub fn gutter_width(&self) -> usize {
+        match self.line_numbers {
+            LineNumbers::Off => 0,
+            _ => self.total_lines().to_string().len().max(3) + 1,
+        }
+    }
+
+    /// Columns available for text, right of the gutter
+    pub fn text_columns(&self) -> usize {
+        (self.dimensions.0 as usize)
+            .saturating_sub(self.gutter_width())
+            .max(1)
+    }
+
+    pub fn view(&self) -> View {
+        let first_line = self.first_line();
+        View {
+            cursor: (self.cursor.0, first_line + self.cursor.1),
+            viewport_v_offset: first_line + self.viewport_v_offset,
+            viewport_h_offset: self.viewport_h_offset,
+            selection_anchor: self
+                .selection_anchor
+                .map(|(col, row)| (col, first_line + row)),
+        }
+    }
+
+    /// Show the buffer as `view` sees it. The text may have been edited from
+    /// another pane since, so positions are clamped to it. A memory mapped
+    /// file's window moves to the view's cursor.
+    pub fn set_view(&mut self, view: View) {
+        let last_line = self.total_lines() - 1;
+        self.set_file_cursor((view.cursor.0, view.cursor.1.min(last_line)));
+        self.clamp_cursor();
+
+        let first_line = self.first_line();
+        let last_row = self.len_lines() - 1;
+        self.viewport_v_offset = view
+            .viewport_v_offset
+            .saturating_sub(first_line)
+            .min(last_row);
+        self.viewport_h_offset = view.viewport_h_offset;
+        self.selection_anchor = view
+            .selection_anchor
+            .and_then(|(col, row)| Some((col, row.checked_sub(first_line)?)))
+            .filter(|&(col, row)| row <= last_row && col <= self.line_len(row));
+    }
+
+    pub fn set_message(&mut self, message: impl Into<String>) {
+        self.message = Some((message.into(), Instant::now()));
+        self.dirty = true;
+    }
+
+    /// When the current message times out
+    pub fn message_deadline(&self) -> Option<Instant> {
+        self.message
+            .as_ref()
+            .map(|(_, shown)| *shown + MESSAGE_TIMEOUT)
+    }
+
+    pub fn expire_message(&mut self) {
+        if self
+            .message_deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            self.message = None;
+            self.dirty = true;
+        }
+    }
+
+    /// Empty the buffer so its file can be loaded again. The view is restored
+    /// once the new text has loaded.
+    pub fn reset(&mut self) {
+        self.restore_view = Some(self.view());
+        self.rope = Rope::new();
+        self.set_view(View::default());
+        self.history = History::default();
+        self.highlight_cache = HighlightCache::default();
+        self.save_changed = false;
+        self.last_edit = None;
+        self.backed_up = None;
+        // read-only until the loader starts, so keys already queued aren't
+        // typed into the empty buffer ahead of the file
+        self.loading = Some((0, 0));
+        self.dirty = true;
+    }
+
+    /// Number of rows in the buffer (never zero)
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    /// Line of the file shown in the buffer's first row. Only memory mapped
+    /// files, which show a window of the file, start past the first line.
+    pub fn first_line(&self) -> usize {
+        self.mapped.as_ref().map_or(0, |mapped| mapped.first_line)
+    }
+
+    /// Number of lines in the file, which for memory mapped files may be more
+    /// than are in the buffer
+    pub fn total_lines(&self) -> usize {
+        match &self.mapped {
+            Some(mapped) => mapped.len_lines().max(1),
+            None => self.len_lines(),
+        }
+    }
+
+    /// Move the window of a memory mapped file that is copied into the rope so
+    /// it stays around the cursor
+    pub fn update_window(&mut self) {
+        let Some(mapped) = &mut self.mapped else {
+            return;
+        };
+
+        let total = mapped.len_lines();
+        let row = mapped.first_line + self.cursor.1;
+        let window_end = mapped.first_line + mapped.window_len;
+
+        let near_start = self.cursor.1 < WINDOW_MARGIN && mapped.first_line > 0;
+        let near_end =
+            mapped.window_len.saturating_sub(self.cursor.1) <= WINDOW_MARGIN && window_end < total;
+        let growing = mapped.window_len < WINDOW_LINES && window_end < total;
+        if near_start || near_end || growing {
+            self.move_window(row);
+        }
+    }
+
+    /// Copy the lines around file line `row`, where the cursor is, into the
+    /// rope of a memory mapped file
+    fn move_window(&mut self, row: usize) {
+        let Some(mapped) = &mut self.mapped else {
+            return;
+        };
+
+        let total = mapped.len_lines();
+        let first_line = row
+            .saturating_sub(WINDOW_LINES / 2)
+            .min(total.saturating_sub(WINDOW_LINES));
+        let top = mapped.first_line + self.viewport_v_offset;
+
+        mapped.first_line = first_line;
+        mapped.window_len = WINDOW_LINES.min(total - first_line);
+        self.rope = Rope::from_str(&mapped.text(first_line..first_line + mapped.window_len));
+
+        self.cursor.1 = row - first_line;
+        self.viewport_v_offset = top.saturating_sub(first_line);
+        self.selection_anchor = None;
+        self.highlight_cache = HighlightCache::default();
+        self.clamp_cursor();
+    }
+
+    /// Cursor position in the file rather than in the buffer's rows
+    pub fn file_cursor(&self) -> (usize, usize) {
+        (self.cursor.0, self.first_line() + self.cursor.1)
+    }
+
+    /// Put the cursor at a position in the file, moving the window of a
+    /// memory mapped file there if it is outside it
+    pub fn set_file_cursor(&mut self, (col, row): (usize, usize)) {
+        if let Some(mapped) = &self.mapped {
+            if !(mapped.first_line..mapped.first_line + mapped.window_len).contains(&row) {
+                self.move_window(row);
+            }
+        }
+        self.cursor = (col, row - self.first_line());
+    }
+
+    /// Text of line `row` of the file, which for memory mapped files needn't
+    /// be in the buffer
+    pub fn file_line(&self, row: usize) -> Cow<'_, str> {
+        match &self.mapped {
+            Some(mapped) => mapped.text(row..row + 1).into(),
+            None => self.line(row).into(),
+        }
+    }
+
+    /// Why the buffer can't be edited right now, if it can't
+    pub fn read_only_reason(&self) -> Option<&'static str> {
+        if self.loading.is_some() {
+            Some("Buffer is still loading")
+        } else if self.read_only {
+            Some("Buffer is read-only")
+        } else {
+            None
+        }
+    }
+
+    /// Length of `row` in chars, not counting the line break
+    pub fn line_len(&self, row: usize) -> usize {
+        self.line(row).len_chars()
+    }
+
+    /// Contents of `row` without the line break
+    pub fn line(&self, row: usize) -> RopeSlice<'_> {
+        let line = self.rope.line(row);
+        match line.chars().last() {
+            Some('\n') => line.slice(..line.len_chars() - 1),
+            _ => line,
+        }
+    }
+
+    /// Screen column of `pos`, with tabs expanded to the next tab stop and
+    /// wide characters taking two columns
+    pub fn visual_col(&self, pos: (usize, usize)) -> usize {
+        let line = self.line(pos.1).to_string();
+        graphemes(&line)
+            .take_while(|&(col, _)| col < pos.0)
+            .fold(0, |vcol, (_, g)| {
+                vcol + grapheme_width(g, vcol, self.tab_width)
+            })
+    }
+
+    /// Column of the grapheme in `row` covering screen column `vcol`, or the
+    /// end of the row if it is shorter
+    pub fn col_at_visual(&self, row: usize, vcol: usize) -> usize {
+        let line = self.line(row).to_string();
+        let mut end = 0;
+        for (col, g) in graphemes(&line) {
+            end += grapheme_width(g, end, self.tab_width);
+            if end > vcol {
+                return col;
+            }
+        }
+        self.line_len(row)
+    }
+
+    /// Columns of `row` where each of its screen rows starts, when long lines
+    /// wrap. A line filling its last screen row exactly gets an empty one
+    /// after it, for the cursor at its end.
+    pub fn wrap_starts(&self, row: usize) -> Vec<usize> {
+        let columns = self.text_columns();
+        let line = self.line(row).to_string();
+        let mut starts = vec![0];
+        let (mut vcol, mut start_vcol) = (0, 0);
+        for (col, g) in graphemes(&line) {
+            let width = grapheme_width(g, vcol, self.tab_width);
+            if vcol + width - start_vcol > columns && vcol > start_vcol {
+                starts.push(col);
+                start_vcol = vcol;
+            }
+            vcol += width;
+        }
+        if vcol > start_vcol && vcol - start_vcol >= columns {
+            starts.push(self.line_len(row));
+        }
+        starts
+    }
+
+    /// Where `pos` is shown in the text area, as (screen row, screen column)
+    /// from its top left corner. Positions above or left of the view are not
+    /// meaningful.
+    pub fn view_position(&self, pos: (usize, usize)) -> (usize, usize) {
+        if !self.wrap {
+            return (
+                pos.1 - self.viewport_v_offset,
+                self.visual_col(pos) - self.viewport_h_offset,
+            );
+        }
+
+        let starts = self.wrap_starts(pos.1);
+        let segment = starts
+            .iter()
+            .rposition(|&start| start <= pos.0)
+            .unwrap_or(0);
+        let rows_above: usize = (self.viewport_v_offset..pos.1)
+            .map(|row| self.wrap_starts(row).len())
+            .sum();
+        (
+            rows_above + segment,
+            self.visual_col(pos) - self.visual_col((starts[segment], pos.1)),
+        )
+    }
+
+    /// The buffer position shown at a screen row and column of the text area,
+    /// clamped to the text
+    pub fn position_at_view(&self, view_row: usize, view_col: usize) -> (usize, usize) {
+        let last_row = self.len_lines() - 1;
+        if !self.wrap {
+            let row = (view_row + self.viewport_v_offset).min(last_row);
+            return (
+                self.col_at_visual(row, view_col + self.viewport_h_offset),
+                row,
+            );
+        }
+
+        let mut rows_left = view_row;
+        for row in self.viewport_v_offset..=last_row {
+            let starts = self.wrap_starts(row);
+            if rows_left < starts.len() || row == last_row {
+                let segment = rows_left.min(starts.len() - 1);
+                let start = starts[segment];
+                let col = self.col_at_visual(row, self.visual_col((start, row)) + view_col);
+                // stay on this screen row rather than the start of the next
+                return match starts.get(segment + 1) {
+                    Some(&next) if col >= next => (self.prev_grapheme((next, row)), row),
+                    _ => (col, row),
+                };
+            }
+            rows_left -= starts.len();
+        }
+        (0, last_row)
+    }
+
+    /// Column where the grapheme before `pos` starts
+    pub fn prev_grapheme(&self, pos: (usize, usize)) -> usize {
+        let line = self.line(pos.1).to_string();
+        graphemes(&line)
+            .map(|(col, _)| col)
+            .take_while(|&col| col < pos.0)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Column where the grapheme after `pos` starts
+    pub fn next_grapheme(&self, pos: (usize, usize)) -> usize {
+        let line = self.line(pos.1).to_string();
+        let next = graphemes(&line)
+            .map(|(col, g)| col + g.chars().count())
+            .find(|&end| end > pos.0);
+        next.unwrap_or(self.line_len(pos.1))
+    }
+
+    /// Keep the cursor column inside the current row
+    pub fn clamp_cursor(&mut self) {
+        self.cursor.0 = self.cursor.0.min(self.line_len(self.cursor.1));
+    }
+
+    /// Convert a (column, row) position into a char index into the rope
+    pub fn char_idx(&self, pos: (usize, usize)) -> usize {
+        self.rope.line_to_char(pos.1) + pos.0
+    }
+
+    /// Convert a char index into the rope into a (column, row) position
+    pub fn position(&self, char_idx: usize) -> (usize, usize) {
+        let row = self.rope.char_to_line(char_idx);
+        (char_idx - self.rope.line_to_char(row), row)
+    }
+
+    pub fn insert(&mut self, pos: (usize, usize), text: &str) {
+        let idx = self.char_idx(pos);
+        self.history.record_insert(idx, text, self.cursor);
+        self.last_edit = Some(Instant::now());
+        self.highlight_cache.invalidate(pos.1);
+        self.rope.insert(idx, text);
+    }
+
+    pub fn insert_char(&mut self, pos: (usize, usize), c: char) {
+        self.insert(pos, c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Insert `text` at the cursor and move the cursor past it
+    pub fn insert_at_cursor(&mut self, text: &str) {
+        let idx = self.char_idx(self.cursor) + text.chars().count();
+        self.insert(self.cursor, text);
+        self.cursor = self.position(idx);
+    }
+
+    /// Remove the text between `start` and `end` (exclusive)
+    pub fn remove(&mut self, start: (usize, usize), end: (usize, usize)) {
+        let start = self.char_idx(start);
+        let end = self.char_idx(end);
+        let text = self.rope.slice(start..end).to_string();
+        self.history.record_remove(start, &text, self.cursor);
+        self.last_edit = Some(Instant::now());
+        self.highlight_cache
+            .invalidate(self.rope.char_to_line(start));
+        self.rope.remove(start..end);
+    }
+
+    /// Ordered (start, end) of the selection, if anything is selected
+    pub fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let anchor = self.selection_anchor?;
+        // positions are (column, row), so order by row first
+        let key = |pos: (usize, usize)| (pos.1, pos.0);
+
+        match key(self.cursor).cmp(&key(anchor)) {
+            Ordering::Less => Some((self.cursor, anchor)),
+            Ordering::Greater => Some((anchor, self.cursor)),
+            Ordering::Equal => None,
+        }
+    }
+
+    /// Selected columns of `row`. The end is one past the line when its line
+    /// break is selected too.
+    pub fn selection_in_row(&self, row: usize) -> Option<(usize, usize)> {
+        let (start, end) = self.selection()?;
+        if row < start.1 || row > end.1 {
+            return None;
         }
+
+        let from = if row == start.1 { start.0 } else { 0 };
+        let to = if row == end.1 {
+            end.0
+        } else {
+            self.line_len(row) + 1
+        };
+        Some((from, to))
+    }
+
+    pub fn selected_text(&self) -> Option<String> {
+        let (start, end) = self.selection()?;
+        Some(
+            self.rope
+                .slice(self.char_idx(start)..self.char_idx(end))
+                .to_string(),
+        )
+    }
+
+    /// Remove the selected text and put the cursor where it started.
+    /// Returns whether anything was removed.
+    pub fn delete_selection(&mut self) -> bool {
+        let selection = self.selection();
+        self.selection_anchor = None;
+
+        match selection {
+            Some((start, end)) => {
+                self.remove(start, end);
+                self.cursor = start;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finish the current undo step
+    pub fn commit(&mut self, typed: bool) {
+        self.history.commit(self.cursor, typed);
     }
 
+    pub fn undo(&mut self) {
+        if let Some((cursor, changed)) = self.history.undo(&mut self.rope, self.cursor) {
+            self.highlight_cache
+                .invalidate(self.rope.char_to_line(changed));
+            self.cursor = cursor;
+            // the selection may be past the end of the restored text
+            self.selection_anchor = None;
+            self.save_changed = !self.history.is_saved();
+            self.last_edit = self.save_changed.then(Instant::now);
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some((cursor, changed)) = self.history.redo(&mut self.rope) {
+            self.highlight_cache
+                .invalidate(self.rope.char_to_line(changed));
+            self.cursor = cursor;
+            self.selection_anchor = None;
+            self.save_changed = !self.history.is_saved();
+            self.last_edit = self.save_changed.then(Instant::now);
+        }
+    }
+
+    /// Append text to the end of the buffer
+    pub fn append(&mut self, text: &str) {
+        self.highlight_cache.invalidate(self.len_lines() - 1);
+        self.rope.insert(self.rope.len_chars(), text);
+    }
+
+    /// Remove spaces and tabs from the ends of lines, as an edit that can be
+    /// undone. Whitespace just before the cursor stays, so that saving (or
+    /// autosaving) in the middle of typing doesn't take it away.
+    fn trim_trailing_whitespace(&mut self) {
+        let mut trimmed = false;
+        for row in 0..self.len_lines() {
+            let line = self.line(row).to_string();
+            let len = line.chars().count();
+            let mut keep = line.trim_end_matches([' ', '\t']).chars().count();
+            if row == self.cursor.1 {
+                keep = keep.max(self.cursor.0);
+            }
+            if keep < len {
+                self.remove((keep, row), (len, row));
+                trimmed = true;
+            }
+        }
+
+        if trimmed {
+            self.commit(false);
+            self.set_view(self.view());
+        }
+    }
+
+    /// Write the buffer to its file. The text goes to a temporary file next to
+    /// it, which is synced and then renamed over the original, so an
+    /// interrupted save never leaves a half written file behind.
     pub fn save(&mut self) -> Result<(), std::io::Error> {
+        if let Some(reason) = self.read_only_reason() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                reason,
+            ));
+        }
+
+        if self.editorconfig.trim_trailing_whitespace == Some(true) {
+            self.trim_trailing_whitespace();
+        }
+
         if let Some(filename) = &self.filename {
-            let mut file = OpenOptions::new().write(true).create(true).open(filename)?;
+            // write through symlinks rather than replacing them
+            let path = std::fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let mut tmp_name = OsString::from(".");
+            tmp_name.push(path.file_name().unwrap_or_default());
+            tmp_name.push(format!(".{}.tmp", std::process::id()));
+            let tmp_path = dir.join(tmp_name);
 
-            for row in &self.row_buffer {
-                for c in row {
-                    file.write_all(c.to_string().as_bytes())?;
-                }
-                file.write_all("\n".as_bytes())?;
+            if let Err(e) = self.write_atomically(&path, &tmp_path) {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
             }
 
+            // make the rename itself durable
+            #[cfg(unix)]
+            std::fs::File::open(dir)?.sync_all()?;
+
             self.save_changed = false;
+            self.last_edit = None;
+            self.history.mark_saved();
+            self.disk_mtime = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
             Ok(())
         } else {
             Err(std::io::Error::new(
@@ -59,45 +759,374 @@ impl TextBuf {
         }
     }
 
-    pub fn load(filename: &str) -> Result<Self, std::io::Error> {
-        let mut file = OpenOptions::new()
-            .write(false)
-            .create(false)
-            .read(true)
-            .open(filename)?;
+    fn write_atomically(&mut self, path: &Path, tmp_path: &Path) -> Result<(), std::io::Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(tmp_path)?;
 
-        let mut textbuf = TextBuf::new();
+        let line_ending = self.line_ending.as_str();
+        let mut writer = BufWriter::new(file);
+        writer.write_all(self.encoding.bom())?;
+        for chunk in self.rope.chunks() {
+            match self.line_ending {
+                LineEnding::Lf => writer.write_all(&self.encoding.encode(chunk)?)?,
+                _ => writer.write_all(&self.encoding.encode(&chunk.replace('\n', line_ending))?)?,
+            }
+        }
+        if self.final_newline {
+            writer.write_all(&self.encoding.encode(line_ending)?)?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
 
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
-        buf = buf.replace('\t', " ".repeat(TABLENGTH).as_str());
+        if let Ok(metadata) = std::fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
 
-        for line in buf.lines() {
-            let row = line.chars().collect();
-            textbuf.row_buffer.push(row);
+            // only the version the buffer was loaded from, so later saves
+            // and autosaves don't replace it
+            if self.backup && self.backed_up.as_deref() != Some(path) {
+                let mut backup_path = path.as_os_str().to_owned();
+                backup_path.push("~");
+                std::fs::copy(path, backup_path)?;
+                self.backed_up = Some(path.to_path_buf());
+            }
         }
+        file.sync_all()?;
 
-        textbuf.filename = Some(filename.to_string());
-
-        Ok(textbuf)
+        std::fs::rename(tmp_path, path)
     }
 
+    /// Load `filename` in chunks, adding each to the buffer as it is read, so
+    /// the start of the file can be viewed (but not edited) while the rest
+    /// loads. Stops early if the buffer is closed.
     pub fn async_load(filename: &str, textbuf: &Arc<Mutex<TextBuf>>) -> Result<(), std::io::Error> {
         let mut file = OpenOptions::new()
             .write(false)
             .create(false)
             .read(true)
             .open(filename)?;
+        let metadata = file.metadata()?;
+        let total = metadata.len();
 
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
+        {
+            let mut textbuf = textbuf.lock().unwrap();
+            textbuf.filename = Some(filename.to_string());
+            textbuf.loading = Some((0, total));
+            textbuf.disk_mtime = metadata.modified().ok();
+        }
+
+        let result = TextBuf::stream(&mut file, total, textbuf);
+
+        let mut textbuf = textbuf.lock().unwrap();
+        textbuf.loading = None;
+        let properties = textbuf.editorconfig.clone();
+        properties.apply_format(&mut textbuf);
+        if let Some(view) = textbuf.restore_view.take() {
+            textbuf.set_view(view);
+        }
+        textbuf.dirty = true;
+        events::send(Event::Loaded);
+        result
+    }
+
+    fn stream(
+        file: &mut File,
+        total: u64,
+        textbuf: &Arc<Mutex<TextBuf>>,
+    ) -> Result<(), std::io::Error> {
+        let mut chunk = Vec::with_capacity(LOAD_CHUNK_SIZE);
+        let mut decoder = None;
+        let mut bom = 0;
+        let mut breaks = LineBreaks::default();
+        let mut loaded = 0;
+
+        loop {
+            if textbuf.lock().unwrap().closed {
+                return Ok(());
+            }
 
-        for line in buf.lines() {
-            let row = line.chars().collect();
-            textbuf.lock().unwrap().row_buffer.push(row);
-            textbuf.lock().unwrap().dirty = true;
+            chunk.clear();
+            let len = (&mut *file)
+                .take(LOAD_CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            let last = len < LOAD_CHUNK_SIZE;
+
+            let decoder = decoder.get_or_insert_with(|| {
+                let (decoder, len) = Decoder::detect(&chunk, last);
+                bom = len;
+                decoder
+            });
+            let Some(text) = decoder.decode(&chunk[bom..], last) else {
+                // not valid in the detected encoding after all, start over
+                *decoder = Decoder::new(decoder.encoding.fallback());
+                bom = 0;
+                breaks = LineBreaks::default();
+                loaded = 0;
+                TextBuf::restart_stream(file, textbuf)?;
+                continue;
+            };
+            bom = 0;
+
+            let Some(text) = breaks.normalize(&text, last) else {
+                // mixed line breaks, start over keeping them as they are
+                *decoder = Decoder::new(decoder.encoding);
+                bom = decoder.encoding.bom().len();
+                breaks = LineBreaks::keep_all();
+                loaded = 0;
+                TextBuf::restart_stream(file, textbuf)?;
+                continue;
+            };
+            loaded += len as u64;
+
+            let mut textbuf = textbuf.lock().unwrap();
+            textbuf.append(&text);
+            textbuf.encoding = decoder.encoding;
+            textbuf.read_only = decoder.encoding == Encoding::Binary;
+            textbuf.line_ending = breaks.style.unwrap_or_default();
+            textbuf.loading = Some((loaded, total));
+            textbuf.dirty = true;
+            events::send(Event::Loaded);
+
+            if last {
+                break;
+            }
+        }
+
+        // the final line break is kept out of the rope
+        let mut textbuf = textbuf.lock().unwrap();
+        let len = textbuf.rope.len_chars();
+        textbuf.final_newline = len > 0 && textbuf.rope.char(len - 1) == '\n';
+        if textbuf.final_newline {
+            textbuf.rope.remove(len - 1..len);
+            let last_row = textbuf.len_lines() - 1;
+            textbuf.highlight_cache.invalidate(last_row);
         }
 
         Ok(())
     }
+
+    /// Go back to the start of the file and empty the buffer, to read the
+    /// file again a different way
+    fn restart_stream(
+        file: &mut File,
+        textbuf: &Arc<Mutex<TextBuf>>,
+    ) -> Result<(), std::io::Error> {
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut textbuf = textbuf.lock().unwrap();
+        textbuf.rope = Rope::new();
+        textbuf.highlight_cache = HighlightCache::default();
+        Ok(())
+    }
+
+    /// Open `filename` read-only through a memory map and index its lines in
+    /// the background. Only a window of lines around the cursor is copied
+    /// into the buffer. Stops early if the buffer is closed.
+    pub fn async_map(filename: &str, textbuf: &Arc<Mutex<TextBuf>>) -> Result<(), std::io::Error> {
+        let file = File::open(filename)?;
+        let mapped = MappedFile::open(&file)?;
+        let mmap = Arc::clone(&mapped.mmap);
+        let total = mmap.len();
+
+        {
+            let mut textbuf = textbuf.lock().unwrap();
+            textbuf.filename = Some(filename.to_string());
+            textbuf.mapped = Some(mapped);
+            textbuf.read_only = true;
+        }
+
+        let mut offset = 0;
+        loop {
+            if textbuf.lock().unwrap().closed {
+                return Ok(());
+            }
+
+            let end = (offset + LOAD_CHUNK_SIZE).min(total);
+            let starts: Vec<usize> = mmap[offset..end]
+                .iter()
+                .enumerate()
+                .filter(|&(_, &byte)| byte == b'\n')
+                .map(|(idx, _)| offset + idx + 1)
+                .collect();
+
+            let mut textbuf = textbuf.lock().unwrap();
+            if let Some(mapped) = &mut textbuf.mapped {
+                mapped.add_line_starts(&starts, end);
+            }
+            textbuf.loading = (end < total).then_some((end as u64, total as u64));
+            textbuf.update_window();
+            textbuf.dirty = true;
+            events::send(Event::Loaded);
+
+            if end == total {
+                return Ok(());
+            }
+            offset = end;
+        }
+    }
+}
+
+/// Graphemes (what is seen as one character) of `line`, with the char column
+/// each starts at
+pub fn graphemes(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    line.graphemes(true).scan(0, |col, g| {
+        let start = *col;
+        *col += g.chars().count();
+        Some((start, g))
+    })
+}
+
+/// Screen width of grapheme `g` when drawn at screen column `vcol`, with tab
+/// stops every `tab_width` columns. Those without a width of their own, like
+/// control characters, take one column.
+pub fn grapheme_width(g: &str, vcol: usize, tab_width: usize) -> usize {
+    match g {
+        "\t" => tab_width - vcol % tab_width,
+        _ => g.width().max(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_and_save_keeps_bytes() {
+        let dir = std::env::temp_dir().join(format!("edrs-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        let filename = path.to_str().unwrap();
+
+        let files: &[&[u8]] = &[
+            b"a\nb\n",
+            b"a\r\nb\r\n",
+            b"a\rb\r",
+            b"a\r\nb\nc\r\n",
+            b"a\nb\r\nc\rd\n",
+            b"a\rb\r\nc\nd",
+            b"a\r\nb\rc",
+            b"no final newline",
+            b"",
+            b"\xEF\xBB\xBFbom\r\nline\r\n",
+            b"\xFF\xFEu\x00t\x00f\x00\r\x00\n\x00",
+        ];
+        for &bytes in files {
+            std::fs::write(&path, bytes).unwrap();
+            let textbuf = Arc::new(Mutex::new(TextBuf::new()));
+            TextBuf::async_load(filename, &textbuf).unwrap();
+
+            let mut textbuf = textbuf.lock().unwrap();
+            textbuf.save().unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), bytes, "{bytes:?}");
+        }
+
+        // not UTF-8 after the first chunk, so read again as Windows-1252
+        let mut bytes = vec![b'a'; LOAD_CHUNK_SIZE + 10];
+        bytes.extend_from_slice(b"caf\xE9\n");
+        std::fs::write(&path, &bytes).unwrap();
+        let textbuf = Arc::new(Mutex::new(TextBuf::new()));
+        TextBuf::async_load(filename, &textbuf).unwrap();
+        let mut textbuf = textbuf.lock().unwrap();
+        assert_eq!(textbuf.encoding, Encoding::Windows1252);
+        assert_eq!(textbuf.len_lines(), 1);
+        textbuf.save().unwrap();
+        assert!(std::fs::read(&path).unwrap() == bytes);
+        drop(textbuf);
+
+        let textbuf = Arc::new(Mutex::new(TextBuf::new()));
+        std::fs::write(&path, b"a\r\nb\nc").unwrap();
+        TextBuf::async_load(filename, &textbuf).unwrap();
+        let textbuf = textbuf.lock().unwrap();
+        assert_eq!(textbuf.line_ending, LineEnding::Lf);
+        assert_eq!(textbuf.rope.to_string(), "a\r\nb\nc");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_over_existing_file() {
+        let dir = std::env::temp_dir().join(format!("edrs-save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        let original = "a longer line than what replaces it\nsecond\n";
+        std::fs::write(&path, original).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        let textbuf = Arc::new(Mutex::new(TextBuf::new()));
+        TextBuf::async_load(path.to_str().unwrap(), &textbuf).unwrap();
+        let mut textbuf = textbuf.lock().unwrap();
+        textbuf.backup = true;
+
+        textbuf.rope = Rope::from_str("short");
+        textbuf.save().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "short\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        // later saves keep the backup of the file as it was loaded
+        textbuf.rope = Rope::from_str("shorter");
+        textbuf.save().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "shorter\n");
+        let backup = dir.join("file.txt~");
+        assert_eq!(std::fs::read_to_string(backup).unwrap(), original);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mapped_file_search_and_views() {
+        let dir = std::env::temp_dir().join(format!("edrs-mmap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large.txt");
+
+        let mut text: String = (0..WINDOW_LINES * 3)
+            .map(|n| format!("line {n}\n"))
+            .collect();
+        text.push_str("needle\n");
+        std::fs::write(&path, text).unwrap();
+        let textbuf = Arc::new(Mutex::new(TextBuf::new()));
+        TextBuf::async_map(path.to_str().unwrap(), &textbuf).unwrap();
+
+        let mut textbuf = textbuf.lock().unwrap();
+        let search = Search {
+            query: "needle".to_string(),
+            ..Search::default()
+        };
+        let (start, _) = search.find(&textbuf, textbuf.file_cursor(), true).unwrap();
+        assert_eq!(start, (0, WINDOW_LINES * 3));
+        textbuf.set_file_cursor(start);
+        assert_eq!(textbuf.line(textbuf.cursor.1).to_string(), "needle");
+
+        // wrapping around, back to the same match and to the start of the file
+        let (start, _) = search.find(&textbuf, (1, start.1), true).unwrap();
+        textbuf.set_file_cursor(start);
+        assert_eq!(textbuf.file_cursor(), (0, WINDOW_LINES * 3));
+        let search = Search {
+            query: "line 0".to_string(),
+            ..search
+        };
+        let (start, _) = search.find(&textbuf, textbuf.file_cursor(), true).unwrap();
+        textbuf.set_file_cursor(start);
+        assert_eq!((textbuf.first_line(), textbuf.cursor), (0, (0, 0)));
+
+        // a pane elsewhere in the file moves the window, and this one's view
+        // still finds its line afterwards
+        textbuf.set_file_cursor((0, WINDOW_LINES * 3));
+        let view = textbuf.view();
+        textbuf.set_view(View::default());
+        assert_eq!(textbuf.first_line(), 0);
+        textbuf.set_view(view);
+        assert_eq!(textbuf.file_cursor(), (0, WINDOW_LINES * 3));
+        assert_eq!(textbuf.line(textbuf.cursor.1).to_string(), "needle");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,292 @@
+use crate::textbuf::View;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub fn contains(&self, (x, y): (u16, u16)) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Split {
+    Horizontal, // one pane above the other
+    Vertical,   // side by side, with a separator column between them
+}
+
+/// A window showing one of the editor's buffers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pane {
+    pub buffer: usize, // index into `Editor::buffers`
+    pub view: View,
+}
+
+/// Tree of panes. Panes are numbered in order, left to right and top to bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layout {
+    Pane(Pane),
+    Split {
+        split: Split,
+        ratio: f32, // share of the space given to `first`
+        first: Box<Layout>,
+        second: Box<Layout>,
+    },
+}
+
+impl Layout {
+    pub fn count(&self) -> usize {
+        match self {
+            Layout::Pane(_) => 1,
+            Layout::Split { first, second, .. } => first.count() + second.count(),
+        }
+    }
+
+    /// Every pane with the area it covers
+    pub fn panes(&self, area: Rect) -> Vec<(&Pane, Rect)> {
+        let mut panes = Vec::new();
+        self.walk(area, &mut |layout, rect| {
+            if let Layout::Pane(pane) = layout {
+                panes.push((pane, rect));
+            }
+        });
+        panes
+    }
+
+    pub fn panes_mut(&mut self, area: Rect) -> Vec<(&mut Pane, Rect)> {
+        match self {
+            Layout::Pane(pane) => vec![(pane, area)],
+            Layout::Split {
+                split,
+                ratio,
+                first,
+                second,
+            } => {
+                let (first_area, second_area, _) = divide(area, *split, *ratio);
+                let mut panes = first.panes_mut(first_area);
+                panes.extend(second.panes_mut(second_area));
+                panes
+            }
+        }
+    }
+
+    pub fn pane(&self, idx: usize) -> &Pane {
+        match self {
+            Layout::Pane(pane) => pane,
+            Layout::Split { first, second, .. } => match idx.checked_sub(first.count()) {
+                None => first.pane(idx),
+                Some(idx) => second.pane(idx),
+            },
+        }
+    }
+
+    pub fn pane_mut(&mut self, idx: usize) -> &mut Pane {
+        match self {
+            Layout::Pane(pane) => pane,
+            Layout::Split { first, second, .. } => match idx.checked_sub(first.count()) {
+                None => first.pane_mut(idx),
+                Some(idx) => second.pane_mut(idx),
+            },
+        }
+    }
+
+    /// Columns between side by side panes
+    pub fn separators(&self, area: Rect) -> Vec<Rect> {
+        let mut separators = Vec::new();
+        self.walk(area, &mut |layout, rect| {
+            if let Layout::Split { split, ratio, .. } = layout {
+                let (_, _, separator) = divide(rect, *split, *ratio);
+                separators.extend(separator);
+            }
+        });
+        separators
+    }
+
+    fn walk<'a>(&'a self, area: Rect, f: &mut impl FnMut(&'a Layout, Rect)) {
+        f(self, area);
+        if let Layout::Split {
+            split,
+            ratio,
+            first,
+            second,
+        } = self
+        {
+            let (first_area, second_area, _) = divide(area, *split, *ratio);
+            first.walk(first_area, f);
+            second.walk(second_area, f);
+        }
+    }
+
+    /// Split pane `idx` in two halves showing the same view. The new pane is
+    /// `idx + 1`.
+    pub fn split(&mut self, idx: usize, split: Split) {
+        match self {
+            Layout::Pane(pane) => {
+                *self = Layout::Split {
+                    split,
+                    ratio: 0.5,
+                    first: Box::new(Layout::Pane(*pane)),
+                    second: Box::new(Layout::Pane(*pane)),
+                }
+            }
+            Layout::Split { first, second, .. } => match idx.checked_sub(first.count()) {
+                None => first.split(idx, split),
+                Some(idx) => second.split(idx, split),
+            },
+        }
+    }
+
+    /// Remove pane `idx`, giving its space to its sibling. Returns the index
+    /// of the pane that took its place, or `None` if it is the only pane.
+    pub fn close(&mut self, idx: usize) -> Option<usize> {
+        let Layout::Split { first, second, .. } = self else {
+            return None;
+        };
+
+        let first_count = first.count();
+        match idx.checked_sub(first_count) {
+            None if matches!(**first, Layout::Pane(_)) => {
+                *self = take(second);
+                Some(idx)
+            }
+            None => first.close(idx),
+            Some(0) if matches!(**second, Layout::Pane(_)) => {
+                *self = take(first);
+                Some(idx - 1)
+            }
+            Some(idx) => second.close(idx).map(|idx| idx + first_count),
+        }
+    }
+
+    /// Move the nearest border of pane `idx` that runs across `direction` one
+    /// cell that way. Returns whether there was such a border.
+    pub fn resize(&mut self, idx: usize, direction: Direction, area: Rect) -> bool {
+        let Layout::Split {
+            split,
+            ratio,
+            first,
+            second,
+        } = self
+        else {
+            return false;
+        };
+
+        let (first_area, second_area, _) = divide(area, *split, *ratio);
+        let first_count = first.count();
+        let in_first = idx < first_count;
+        let resized = if in_first {
+            first.resize(idx, direction, first_area)
+        } else {
+            second.resize(idx - first_count, direction, second_area)
+        };
+        if resized {
+            return true;
+        }
+
+        let extent = match (split, direction) {
+            (Split::Horizontal, Direction::Up | Direction::Down) => area.height,
+            (Split::Vertical, Direction::Left | Direction::Right) => area.width,
+            _ => return false,
+        };
+
+        let step = 1.0 / extent.max(1) as f32;
+        *ratio = match direction {
+            Direction::Down | Direction::Right => *ratio + step,
+            Direction::Up | Direction::Left => *ratio - step,
+        }
+        .clamp(step, 1.0 - step);
+        true
+    }
+
+    /// The pane next to pane `idx` in `direction`, if any
+    pub fn neighbour(&self, idx: usize, direction: Direction, area: Rect) -> Option<usize> {
+        let panes = self.panes(area);
+        let from = panes[idx].1;
+
+        panes
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(_, rect))| match direction {
+                Direction::Up => rect.y + rect.height <= from.y && overlaps_x(rect, from),
+                Direction::Down => rect.y >= from.y + from.height && overlaps_x(rect, from),
+                Direction::Left => rect.x + rect.width <= from.x && overlaps_y(rect, from),
+                Direction::Right => rect.x >= from.x + from.width && overlaps_y(rect, from),
+            })
+            .min_by_key(|&(_, &(_, rect))| match direction {
+                Direction::Up => from.y - rect.y - rect.height,
+                Direction::Down => rect.y - from.y - from.height,
+                Direction::Left => from.x - rect.x - rect.width,
+                Direction::Right => rect.x - from.x - from.width,
+            })
+            .map(|(idx, _)| idx)
+    }
+}
+
+/// Move a subtree out, leaving a copy of its first pane behind
+fn take(layout: &mut Layout) -> Layout {
+    let placeholder = Layout::Pane(*layout.pane(0));
+    std::mem::replace(layout, placeholder)
+}
+
+fn overlaps_x(a: Rect, b: Rect) -> bool {
+    a.x < b.x + b.width && b.x < a.x + a.width
+}
+
+fn overlaps_y(a: Rect, b: Rect) -> bool {
+    a.y < b.y + b.height && b.y < a.y + a.height
+}
+
+/// Areas of the two halves of a split, and of the separator between them
+fn divide(area: Rect, split: Split, ratio: f32) -> (Rect, Rect, Option<Rect>) {
+    match split {
+        Split::Horizontal => {
+            let top =
+                ((area.height as f32 * ratio).round() as u16).clamp(1, area.height.max(2) - 1);
+            (
+                Rect {
+                    height: top,
+                    ..area
+                },
+                Rect {
+                    y: area.y + top,
+                    height: area.height.saturating_sub(top),
+                    ..area
+                },
+                None,
+            )
+        }
+        Split::Vertical => {
+            let usable = area.width.saturating_sub(1);
+            let left = ((usable as f32 * ratio).round() as u16).clamp(1, usable.max(2) - 1);
+            (
+                Rect {
+                    width: left,
+                    ..area
+                },
+                Rect {
+                    x: area.x + left + 1,
+                    width: usable.saturating_sub(left),
+                    ..area
+                },
+                Some(Rect {
+                    x: area.x + left,
+                    width: 1,
+                    ..area
+                }),
+            )
+        }
+    }
+}